@@ -6,235 +6,309 @@ use syn::spanned::Spanned;
 
 #[proc_macro_attribute]
 pub fn get(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route_with_method(attr, item, "GET")
+}
+
+#[proc_macro_attribute]
+pub fn post(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route_with_method(attr, item, "POST")
+}
+
+#[proc_macro_attribute]
+pub fn put(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route_with_method(attr, item, "PUT")
+}
+
+#[proc_macro_attribute]
+pub fn patch(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route_with_method(attr, item, "PATCH")
+}
+
+#[proc_macro_attribute]
+pub fn delete(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route_with_method(attr, item, "DELETE")
+}
+
+#[proc_macro_attribute]
+pub fn options(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route_with_method(attr, item, "OPTIONS")
+}
+
+#[proc_macro_attribute]
+pub fn head(attr: TokenStream, item: TokenStream) -> TokenStream {
+    route_with_method(attr, item, "HEAD")
+}
+
+/// Shared by the method macros, builds a route for `item` and registers it under `method`
+fn route_with_method(attr: TokenStream, item: TokenStream, method: &str) -> TokenStream {
     let item_fn: syn::ItemFn = syn::parse(item).unwrap();
-    let value: syn::LitStr = syn::parse(attr).unwrap();
+    let args: RouteArgs = match syn::parse(attr) {
+        Ok(args) => args,
+        Err(e) => return e.into_compile_error().into(),
+    };
+    let value = args.path;
 
     let sig = item_fn.sig;
     let name = sig.ident.clone();
+    let route_name = args
+        .name
+        .map_or_else(|| name.to_string(), |name| name.value());
     let body = item_fn.block.deref();
     let return_type = sig.output;
+    let method = syn::Ident::new(method, proc_macro::Span::call_site().into());
 
     let fn_args = sig.inputs;
     let is_body_args = !fn_args.is_empty();
-    //eprintln!("LEN: {}", body_args.len());
-
-    let mut path = value.value();
-    /*match path_token {
-        syn::NestedMeta::Meta(_) => panic!("IN TOKEN MATCH!"),
-        syn::NestedMeta::Lit(e) => match e {
-            syn::Lit::Str(e) => {
-                path = e.value();
-            }
-            syn::Lit::ByteStr(_) => panic!("IN TOKEN MATCH!"),
-            syn::Lit::Byte(_) => panic!("IN TOKEN MATCH!"),
-            syn::Lit::Char(_) => panic!("IN TOKEN MATCH!"),
-            syn::Lit::Int(_) => panic!("IN TOKEN MATCH!"),
-            syn::Lit::Float(_) => panic!("IN TOKEN MATCH!"),
-            syn::Lit::Bool(_) => panic!("IN TOKEN MATCH!"),
-            syn::Lit::Verbatim(_) => panic!("IN TOKEN MATCH!"),
-        },
-    };*/
-
-    let new_wildcard = if path.contains("/:") {
-        let path_clone = path.clone();
-        let mut iter = path_clone.split(':');
-        path = iter.next().unwrap().to_string();
-        let id = iter.next().unwrap().to_string();
-        if path.len() != 1 {
-            path.pop();
-        };
-        quote! {get_route = get_route.set_wildcard(#id.into());}
-    } else {
-        quote! {}
-    };
 
-    let span = return_type.span();
-    let return_error = match return_type {
-        syn::ReturnType::Default => Some(
-            syn::Error::new(span, "You're forgetting to return something...").into_compile_error(),
-        ),
-        _ => None,
-    };
+    let path = value.value();
+    if let Err(e) = check_path(&value) {
+        return e.into_compile_error().into();
+    }
 
-    if let Some(e) = return_error {
-        return e.into();
+    if let syn::ReturnType::Default = return_type {
+        return syn::Error::new(
+            return_type.span(),
+            "You're forgetting to return something...",
+        )
+        .into_compile_error()
+        .into();
     }
 
-    // let is_ret_type_res = return_type_str == "Response";
-
-    //    let new_get_body = if is_ret_type_res {
-    //        quote! {
-    //            let mut get_route = GetRouteWithReqAndRes::new()
-    //                .set_path(#path.into());
-    //
-    //            fn body(#body_args) -> Response {
-    //                #body.into()
-    //            }
-    //
-    //            get_route = get_route.set_body(body);
-    //        }
-    let new_get_body = if is_body_args {
-        let mut fn_args_iter = fn_args.iter();
-        let first_arg_name = fn_args_iter.next().unwrap();
-        let arg_type = match first_arg_name {
+    let new_body = if is_body_args {
+        let arg_type = match fn_args.first().unwrap() {
             syn::FnArg::Typed(i) => i.to_owned(),
-            _ => todo!(),
+            arg => {
+                return syn::Error::new(arg.span(), "Route handlers can't take `self`")
+                    .into_compile_error()
+                    .into()
+            }
         };
 
         quote! {
-            let mut get_route = GetRouteWithReqAndRes::new()
-                .set_path(#path.into());
-
-            fn body<'b>(try_from_req: &'b mut Request, _sock: &'b mut std::net::TcpStream) -> Response {
-                let #arg_type = try_from_req.into();
+            let mut route = RouteWithReqAndRes::new()
+                .set_path(#path.into())
+                .set_name(#route_name)
+                .set_method(Method::#method);
+
+            fn body<'b>(try_from_req: &'b mut Request, _conn: &mut dyn Connection) -> Response {
+                let #arg_type = match FromRequest::from_request(try_from_req) {
+                    Ok(arg) => arg,
+                    Err(res) => return res,
+                };
                 #body.into()
             }
 
-            // OG
-            // fn body(#body_args) -> Response {
-            // #body.into()
-            // }
-
-            get_route = get_route.set_body(body);
+            route = route.set_body(body);
         }
     } else {
         quote! {
-            let mut get_route = BasicGetRoute::new()
-                .set_path(#path.into());
+            let mut route = BasicRoute::new()
+                .set_path(#path.into())
+                .set_name(#route_name)
+                .set_method(Method::#method);
 
             fn body() -> Response {
                 #body.into()
             }
 
-            get_route = get_route.set_body(body);
+            route = route.set_body(body);
         }
     };
 
     let output = quote! {
         fn #name() -> Box<dyn Route> {
-            /*let mut get_route = GetRoute::new()
-                .set_path(#path.into())
-                .set_is_args(#is_body_args)
-                .set_is_ret_res(#is_ret_type_res);*/
-
-
-            #new_get_body
-            #new_wildcard
-
-            Box::new(get_route)
+            #new_body
+            Box::new(route)
         }
     };
 
     output.into()
 }
 
+/// Turns a function into a `Catcher` for an error status
+///
+/// The function can take the `&Request` that failed, and returns anything `Into<Response>`.
+/// The response gets the caught status unless it sets another one.
+///
+/// ```ignore
+/// #[catch(404)]
+/// fn not_found(req: &Request) -> String {
+///     format!("nothing at {}", req.get_path())
+/// }
+///
+/// let config = Config::new().catchers(vec![not_found()]);
+/// ```
 #[proc_macro_attribute]
-pub fn post(attr: TokenStream, item: TokenStream) -> TokenStream {
-    //eprintln!("{:#?}\n{:#?}", attr, item);
-    let item: syn::ItemFn = syn::parse(item).unwrap();
-    let value: syn::LitStr = syn::parse(attr).unwrap();
-
-    let fn_args = item.sig.inputs;
-    let name = item.sig.ident.clone();
-    let body = item.block.deref();
-    let return_type = item.sig.output;
-
-    let is_body_args = !fn_args.is_empty();
-
-    let mut path = value.value();
-    let new_wildcard = if path.contains("/:") {
-        let path_clone = path.clone();
-        let mut iter = path_clone.split(':');
-        path = iter.next().unwrap().to_string();
-        let id = iter.next().unwrap().to_string();
-        if path.len() != 1 {
-            path.pop();
-        };
-        quote! {post_route = post_route.set_wildcard(#id.into());}
-    } else {
-        quote! {}
+pub fn catch(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item_fn: syn::ItemFn = syn::parse(item).unwrap();
+    let status: syn::LitInt = match syn::parse(attr) {
+        Ok(status) => status,
+        Err(e) => return e.into_compile_error().into(),
     };
+    if let Err(e) = status.base10_parse::<u16>() {
+        return e.into_compile_error().into();
+    }
 
-    let return_error = match return_type {
-        syn::ReturnType::Default => Some(
-            syn::Error::new(
-                return_type.span(),
-                "You're forgetting to return something...",
-            )
-            .into_compile_error(),
-        ),
-        _ => None,
-    };
+    let sig = item_fn.sig;
+    let name = sig.ident;
+    let body = item_fn.block.deref();
+    let return_type = sig.output;
+    let fn_args = sig.inputs;
 
-    if let Some(e) = return_error {
-        return e.into();
+    if let syn::ReturnType::Default = return_type {
+        return syn::Error::new(
+            return_type.span(),
+            "You're forgetting to return something...",
+        )
+        .into_compile_error()
+        .into();
     }
 
-    let new_post_body = if is_body_args {
-        let first_arg_name = fn_args.first().unwrap();
-        let arg_type = match first_arg_name {
-            syn::FnArg::Typed(i) => i.to_owned(),
-            _ => todo!(),
-        };
-        // NOTE: Gets arg name and type
-        //        let arg_name_pat = match &arg_type.pat.deref() {
-        //            syn::Pat::Ident(i) => i.to_owned(),
-        //            _ => todo!(),
-        //        };
-        //        let arg_name_type = match &arg_type.ty.deref() {
-        //            syn::Type::Path(i) => i.to_owned(),
-        //            _ => todo!(),
-        //        };
-        //
-        //        let arg_name_type = &arg_name_type.path.segments.first().unwrap().ident;
-        quote! {
-
-            let mut post_route = PostRouteWithReqAndRes::new()
-                .set_path(#path.into());
+    let handler = match fn_args.len() {
+        0 => quote! { |_req| body() },
+        1 => quote! { body },
+        _ => {
+            return syn::Error::new(
+                fn_args.span(),
+                "catchers take at most one argument, the `&Request`",
+            )
+            .into_compile_error()
+            .into()
+        }
+    };
 
-            fn body<'b>(try_from_req: &'b mut Request, _sock: &'b mut std::net::TcpStream) -> Response {
-                let #arg_type = try_from_req.into();
+    let output = quote! {
+        fn #name() -> Catcher {
+            fn body(#fn_args) -> Response {
                 #body.into()
             }
 
-            post_route = post_route.set_body(body);
+            Catcher::new(#status, #handler)
         }
-    } else {
-        quote! {
-            let mut post_route = BasicPostRoute::new()
-                .set_path(#path.into());
+    };
 
-            fn body() -> Response {
-                #body.into()
-            }
+    output.into()
+}
 
-            post_route = post_route.set_body(body);
+/// Implements `FromParams` for a struct, filling each field from the path parameter of the
+/// same name
+///
+/// ```ignore
+/// #[derive(FromParams)]
+/// struct PostId {
+///     id: u32,
+///     post_id: u32,
+/// }
+///
+/// #[get("/users/:id/posts/:post_id")]
+/// fn post(ids: Path<PostId>) -> String {
+///     format!("post {} by {}", ids.post_id, ids.id)
+/// }
+/// ```
+#[proc_macro_derive(FromParams)]
+pub fn derive_from_params(item: TokenStream) -> TokenStream {
+    let input: syn::DeriveInput = syn::parse(item).unwrap();
+    let name = input.ident;
+
+    let fields = match input.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => fields.named,
+        _ => {
+            return syn::Error::new(
+                name.span(),
+                "FromParams can only be derived for structs with named fields",
+            )
+            .into_compile_error()
+            .into()
         }
     };
 
+    let field_names = fields.iter().map(|field| field.ident.as_ref().unwrap());
+    let param_names = field_names.clone().map(|ident| ident.to_string());
+
     let output = quote! {
-        fn #name() -> Box<dyn Route> {
-            /*let mut post_route = PostRoute::new()
-                .set_path(#path.into())
-                .set_is_args(#is_body_args)
-                .set_is_ret_res(#is_ret_type_res);*/
+        impl FromParams for #name {
+            fn from_params(params: &[(String, String)]) -> Result<Self, ParamError> {
+                Ok(#name {
+                    #(#field_names: parse_param(params, #param_names)?,)*
+                })
+            }
+        }
+    };
+
+    output.into()
+}
+
+/// The arguments of the route macros, a path and an optional name
+///
+/// Routes are named after their function unless `name = "..."` is given.
+struct RouteArgs {
+    path: syn::LitStr,
+    name: Option<syn::LitStr>,
+}
 
-            #new_post_body
-            #new_wildcard
+impl syn::parse::Parse for RouteArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let path = input.parse()?;
+        let mut name = None;
 
-            Box::new(post_route)
+        if input.parse::<Option<syn::Token![,]>>()?.is_some() && !input.is_empty() {
+            let key: syn::Ident = input.parse()?;
+            if key != "name" {
+                return Err(syn::Error::new(key.span(), "expected `name = \"...\"`"));
+            }
+            input.parse::<syn::Token![=]>()?;
+            name = Some(input.parse()?);
+            input.parse::<Option<syn::Token![,]>>()?;
         }
-    };
 
-    /*let output = quote! {
-        fn #name() -> (String, Vec<u8>, Method) {
-            fn body() #output {
-                #body
+        Ok(RouteArgs { path, name })
+    }
+}
+
+/// Rejects route paths the router can't match
+///
+/// `:name` matches a single segment and `*name` the rest of the path, so a catch-all has to
+/// be the last segment.
+fn check_path(path: &syn::LitStr) -> syn::Result<()> {
+    let value = path.value();
+    let mut segments = value.split('/').filter(|s| !s.is_empty()).peekable();
+    let mut names = Vec::new();
+
+    while let Some(segment) = segments.next() {
+        let is_last = segments.peek().is_none();
+        let name = match segment.strip_prefix('*') {
+            Some(_) if !is_last => {
+                return Err(syn::Error::new(
+                    path.span(),
+                    format!("catch-all `{segment}` must be the last segment of the path"),
+                ));
             }
+            Some(name) => name,
+            // A bare `:` at the end is the old wildcard syntax
+            None if segment == ":" && is_last => continue,
+            None => match segment.strip_prefix(':') {
+                Some("") => {
+                    return Err(syn::Error::new(
+                        path.span(),
+                        "parameters need a name, like `:id`",
+                    ));
+                }
+                Some(name) => name,
+                None => continue,
+            },
+        };
 
-            (#path.into(), body().into(), Method::POST)
+        if !name.is_empty() && names.contains(&name) {
+            return Err(syn::Error::new(
+                path.span(),
+                format!("parameter `{name}` is used more than once"),
+            ));
         }
-    };*/
+        names.push(name);
+    }
 
-    output.into()
+    Ok(())
 }
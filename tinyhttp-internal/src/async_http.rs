@@ -2,269 +2,229 @@ use std::cell::RefCell;
 use std::path::Path;
 use std::rc::Rc;
 
-
-use tokio::{fs::File, io::AsyncReadExt, io::AsyncWriteExt, select};
+use tokio::{
+    fs::File,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+};
 
 use crate::{
-    config::{Config, HttpListener},
+    chunked::{decode_chunked, ChunkedLimits},
+    config::{Config, HttpListener, Method},
+    connection::{Connection, ConnectionAddr, MemoryConnection},
+    headers::HeaderMap,
+    listener::Listener,
+    parser::{
+        add_header, body_length, finish_line, parse_header_line, parse_status_line, read_err,
+        BodyLength, MAX_EMPTY_LINES,
+    },
     request::{Request, RequestError},
-    response::Response,
+    response::{Response, StatusCode},
+    router::RouteMatch,
+    url::normalize_path,
 };
 
-
 pub(crate) async fn start_http(http: HttpListener) {
+    http.config.log_route_table();
+    let local_addr = http.socket.local_addr().ok();
     loop {
         let config = http.config.clone();
-        select! {
-            result = http.socket.accept() => {
-                let (mut conn, _) = result.unwrap();
-                    parse_request(&mut conn, config).await;
+        match &http.socket {
+            Listener::Tcp(listener) => {
+                let (mut conn, peer_addr) = listener.accept().await.unwrap();
+                parse_request(
+                    &mut conn,
+                    config,
+                    Some(peer_addr.into()),
+                    local_addr.clone(),
+                )
+                .await;
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (mut conn, peer_addr) = listener.accept().await.unwrap();
+                let peer_addr = ConnectionAddr::Unix(peer_addr.as_pathname().map(Into::into));
+                parse_request(&mut conn, config, Some(peer_addr), local_addr.clone()).await;
             }
         }
     }
 }
 
-fn build_and_parse_req(buf: Vec<u8>) -> Result<Request, RequestError> {
-    #[cfg(feature = "log")]
-    log::trace!("build_and_parse_req ~~> buf: {:?}", buf);
-
-    let mut safe_http_index = buf.windows(2).enumerate();
-    let status_line_index_opt = safe_http_index
-        .find(|(_, w)| matches!(*w, b"\r\n"))
-        .map(|(i, _)| i);
-
-    let status_line_index = if let Some(status) = status_line_index_opt {
-        status
-    } else {
-        #[cfg(feature = "log")]
-        log::error!("failed parsing status line!");
+/// Reads a single CRLF terminated line of at most `limit` bytes (excluding the CRLF)
+pub(crate) async fn read_line_limited<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    limit: usize,
+    too_long: RequestError,
+    malformed: RequestError,
+) -> Result<Vec<u8>, RequestError> {
+    let mut line = Vec::new();
+    if let Err(e) = (&mut *reader)
+        .take((limit as u64).saturating_add(2))
+        .read_until(b'\n', &mut line)
+        .await
+    {
+        return Err(read_err(e, malformed));
+    }
 
-        return Err(RequestError::StatusLineErr);
-    };
+    finish_line(line, limit, too_long, malformed)
+}
 
-    let first_header_index = if let Some(first_header_index) = safe_http_index
-        .find(|(_, w)| matches!(*w, b"\r\n"))
-        .map(|(i, _)| i)
-    {
-        first_header_index
-    } else {
-        #[cfg(feature = "log")]
-        log::warn!("no headers found!");
+async fn build_and_parse_req<P: AsyncRead + Unpin>(
+    buf_reader: &mut BufReader<P>,
+    config: &Config,
+) -> Result<Request, RequestError> {
+    let mut empty_lines = 0;
+    let status_line = loop {
+        let line = read_line_limited(
+            buf_reader,
+            config.get_max_request_line_len(),
+            RequestError::RequestLineTooLong,
+            RequestError::StatusLineErr,
+        )
+        .await?;
+        if !line.is_empty() {
+            break line;
+        }
 
-        0usize
+        empty_lines += 1;
+        if empty_lines > MAX_EMPTY_LINES {
+            return Err(RequestError::StatusLineErr);
+        }
     };
+    let status_line = parse_status_line(&status_line)?;
 
-    #[cfg(feature = "log")]
-    log::trace!(
-        "STATUS LINE: {:#?}",
-        std::str::from_utf8(&buf[..status_line_index])
-    );
-
-    #[cfg(feature = "log")]
-    log::trace!(
-        "FIRST HEADER: {:#?}",
-        std::str::from_utf8(&buf[status_line_index + 2..first_header_index])
-    );
-
-    let mut headers = Vec::<String>::new();
-    let mut headers_index = vec![first_header_index + 2];
-    while let Some(header_index) = safe_http_index
-        .find(|(_, w)| matches!(*w, b"\r\n"))
-        .map(|(i, _)| i + 2)
-    {
-        #[cfg(feature = "log")]
-        log::trace!("header index: {}", header_index);
+    let mut headers = HeaderMap::new();
+    let mut header_count = 0;
+    let mut header_bytes = 0;
 
-        let header = std::str::from_utf8(&buf[*headers_index.last().unwrap()..header_index - 2])
-            .unwrap()
-            .to_lowercase();
+    loop {
+        let header = read_line_limited(
+            buf_reader,
+            config.get_max_header_bytes() - header_bytes,
+            RequestError::HeadersTooLarge,
+            RequestError::HeadersErr,
+        )
+        .await?;
         if header.is_empty() {
             break;
         }
-        #[cfg(feature = "log")]
-        log::trace!("HEADER: {:?}", headers);
 
-        headers_index.push(header_index);
-        headers.push(header);
+        header_count += 1;
+        header_bytes += header.len();
+        if header_count > config.get_max_header_count() {
+            return Err(RequestError::TooManyHeaders);
+        }
+
+        let (key, val) = parse_header_line(&header)?;
+        add_header(&mut headers, key, val)?;
     }
 
-    let iter_status_line = std::str::from_utf8(&buf[..status_line_index]).unwrap();
+    let raw_body = match body_length(&headers, config.get_max_body_bytes())? {
+        BodyLength::Chunked => {
+            let limits = ChunkedLimits {
+                max_body_bytes: config.get_max_body_bytes(),
+                max_trailer_count: config.get_max_header_count() - header_count,
+                max_trailer_bytes: config.get_max_header_bytes() - header_bytes,
+            };
+            decode_chunked(buf_reader, &mut headers, &limits).await?
+        }
+        BodyLength::Fixed(body_len) => {
+            let mut raw_body = vec![0; body_len];
+            buf_reader
+                .read_exact(&mut raw_body)
+                .await
+                .map_err(|e| read_err(e, RequestError::TruncatedBody))?;
+            raw_body
+        }
+    };
 
-    //let headers = parse_headers(http.to_string());
-    let str_status_line = Vec::from_iter(iter_status_line.split_whitespace());
-    let status_line: Vec<String> = str_status_line.iter().map(|i| i.to_string()).collect();
-    #[cfg(feature = "log")]
-    log::trace!("{:#?}", str_status_line);
-    let body_index = buf
-        .windows(4)
-        .enumerate()
-        .find(|(_, w)| matches!(*w, b"\r\n\r\n"))
-        .map(|(i, _)| i)
-        .unwrap();
-
-    let raw_body = &buf[body_index + 4..];
-    //    #[cfg(feature = "log")]
-    //    log::debug!(
-    //        "BODY (TOP): {:#?}",
-    //        std::str::from_utf8(&buf[body_index + 4..]).unwrap()
-    //    );
     Ok(Request::new(raw_body, headers, status_line, None))
 }
 
-async fn build_res(req: &mut Request, config: &mut Config) -> Response {
+async fn build_res(req: &mut Request, config: &mut Config, conn: &mut dyn Connection) -> Response {
     let status_line = req.get_status_line();
-    let req_path = Rc::new(RefCell::new(status_line[1].clone()));
+    let req_path = Rc::new(RefCell::new(req.get_path().to_owned()));
     #[cfg(feature = "log")]
     log::trace!("build_res -> req_path: {}", req_path.borrow());
 
-    match status_line[0].as_str() {
-        "GET" => match config.get_routes(&mut req_path.borrow_mut()) {
-            Some(route) => {
-                #[cfg(feature = "log")]
-                log::trace!("Found path in routes!");
-
-                let req_new = if route.wildcard().is_some() {
-                    let stat_line = &status_line[1];
-                    let split = stat_line
-                        .split(&(route.get_path().to_string() + "/"))
-                        .last()
-                        .unwrap();
+    let method = match status_line[0].parse::<Method>() {
+        Ok(method) => method,
+        Err(_) => return config.error_response(StatusCode::NOT_IMPLEMENTED, req),
+    };
 
-                    req.set_wildcard(Some(split.into()))
-                } else {
-                    req
-                };
+    if let Some(res) = config.route_table_response(method, req) {
+        return res;
+    }
 
-                /*if route.is_ret_res() {
-                    route.get_body_with_res().unwrap()(req_new.to_owned())
-                } else {
-                    Response::new()
-                        .status_line("HTTP/1.1 200 OK\r\n")
-                        .body(route.to_body(req_new.to_owned()))
-                        .mime("text/plain")
-                }*/
+    // HEAD requests are answered by the GET route, unless one was registered for HEAD
+    let route = config
+        .match_route(method, &req_path.borrow())
+        .or_else(|| match method {
+            Method::HEAD => config.match_route(Method::GET, &req_path.borrow()),
+            _ => None,
+        });
+
+    match route {
+        Some(RouteMatch { route, params }) => {
+            #[cfg(feature = "log")]
+            log::trace!("Found path in routes!");
+
+            req.set_params(params);
+            let res = route.to_res(req.to_owned(), conn);
+            config.fill_error_response(res, req)
+        }
 
-                route.to_res(req_new.to_owned())
-            }
+        None => match config.get_mount() {
+            Some(old_path) if matches!(method, Method::GET | Method::HEAD) => {
+                let path = match normalize_path(&req_path.borrow()) {
+                    Some(file_path) => old_path.to_owned() + &file_path,
+                    None => return config.error_response(StatusCode::BAD_REQUEST, req),
+                };
+                if Path::new(&path).extension().is_none() && config.get_spa() {
+                    let body = read_to_vec(old_path.to_owned() + "/index.html")
+                        .await
+                        .unwrap();
+                    let line = "HTTP/1.1 200 OK\r\n";
 
-            None => match config.get_mount() {
-                Some(old_path) => {
-                    let path = old_path.to_owned() + &status_line[1];
-                    if Path::new(&path).extension().is_none() && config.get_spa() {
-                        let body = read_to_vec(old_path.to_owned() + "/index.html")
-                            .await
-                            .unwrap();
-                        let line = "HTTP/1.1 200 OK\r\n";
+                    Response::new()
+                        .status_line(line)
+                        .body(body)
+                        .mime("text/html")
+                } else if Path::new(&path).is_file() {
+                    let body = read_to_vec(&path).await.unwrap();
+                    let line = "HTTP/1.1 200 OK\r\n";
+                    let mime = mime_guess::from_path(&path)
+                        .first_raw()
+                        .unwrap_or("text/plain");
+                    Response::new().status_line(line).body(body).mime(mime)
+                } else if Path::new(&path).is_dir() {
+                    if Path::new(&(path.to_owned() + "/index.html")).is_file() {
+                        let body = read_to_vec(path + "/index.html").await.unwrap();
 
-                        Response::new()
-                            .status_line(line)
-                            .body(body)
-                            .mime("text/html")
-                    } else if Path::new(&path).is_file() {
-                        let body = read_to_vec(&path).await.unwrap();
-                        let line = "HTTP/1.1 200 OK\r\n";
-                        let mime = mime_guess::from_path(&path)
-                            .first_raw()
-                            .unwrap_or("text/plain");
-                        Response::new().status_line(line).body(body).mime(mime)
-                    } else if Path::new(&path).is_dir() {
-                        if Path::new(&(path.to_owned() + "/index.html")).is_file() {
-                            let body = read_to_vec(path + "/index.html").await.unwrap();
-
-                            let line = "HTTP/1.1 200 OK\r\n";
-                            Response::new()
-                                .status_line(line)
-                                .body(body)
-                                .mime("text/html")
-                        } else {
-                            Response::new()
-                                .status_line("HTTP/1.1 200 OK\r\n")
-                                .body(b"<h1>404 Not Found</h1>".to_vec())
-                                .mime("text/html")
-                        }
-                    } else if Path::new(&(path.to_owned() + ".html")).is_file() {
-                        let body = read_to_vec(path + ".html").await.unwrap();
                         let line = "HTTP/1.1 200 OK\r\n";
                         Response::new()
                             .status_line(line)
                             .body(body)
                             .mime("text/html")
                     } else {
-                        Response::new()
-                            .status_line("HTTP/1.1 404 NOT FOUND\r\n")
-                            .body(b"<h1>404 Not Found</h1>".to_vec())
-                            .mime("text/html")
+                        config.unrouted_response(&req_path.borrow(), req)
                     }
-                }
-
-                None => Response::new()
-                    .status_line("HTTP/1.1 404 NOT FOUND\r\n")
-                    .body(b"<h1>404 Not Found</h1>".to_vec())
-                    .mime("text/html"),
-            },
-        },
-        "POST" => match config.post_routes(&mut req_path.borrow_mut()) {
-            Some(route) => {
-                #[cfg(feature = "log")]
-                log::debug!("POST");
-
-                let req_new = if route.wildcard().is_some() {
-                    let stat_line = &status_line[1];
-
-                    let split = stat_line
-                        .split(&(route.get_path().to_string() + "/"))
-                        .last()
-                        .unwrap();
-
-                    req.set_wildcard(Some(split.into()))
+                } else if Path::new(&(path.to_owned() + ".html")).is_file() {
+                    let body = read_to_vec(path + ".html").await.unwrap();
+                    let line = "HTTP/1.1 200 OK\r\n";
+                    Response::new()
+                        .status_line(line)
+                        .body(body)
+                        .mime("text/html")
                 } else {
-                    req
-                };
-
-                route.to_res(req_new.to_owned())
+                    config.unrouted_response(&req_path.borrow(), req)
+                }
             }
 
-            None => Response::new()
-                .status_line("HTTP/1.1 404 NOT FOUND\r\n")
-                .body(b"<h1>404 Not Found</h1>".to_vec())
-                .mime("text/html"),
+            _ => config.unrouted_response(&req_path.borrow(), req),
         },
-
-        _ => Response::new()
-            .status_line("HTTP/1.1 404 NOT FOUND\r\n")
-            .body(b"<h1>404 Not Found</h1>".to_vec())
-            .mime("text/html"),
-    }
-}
-
-
-pub(crate) async fn read_stream<P: AsyncReadExt + Unpin>(stream: &mut P) -> Vec<u8> {
-    let buffer_size = 1024;
-    let mut request_buffer = vec![];
-    loop {
-        let mut buffer = vec![0; buffer_size];
-        match stream.read(&mut buffer).await {
-            Ok(n) => {
-                if n == 0 {
-                    break;
-                } else if n < buffer_size {
-                    request_buffer.append(&mut buffer[..n].to_vec());
-                    break;
-                } else {
-                    request_buffer.append(&mut buffer);
-                }
-            }
-            Err(e) => {
-                println!("Error: Could not read string!: {}", e);
-                std::process::exit(1);
-            }
-        }
     }
-
-    request_buffer
 }
 
-
 pub async fn read_to_vec<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
     async fn inner(path: &Path) -> std::io::Result<Vec<u8>> {
         use tokio::io::BufReader;
@@ -277,21 +237,24 @@ pub async fn read_to_vec<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
     inner(path.as_ref()).await
 }
 
-
-async fn parse_request<P: AsyncReadExt + AsyncWriteExt + Unpin>(conn: &mut P, mut config: Config) {
-    let buf = read_stream(conn).await;
-    let request = build_and_parse_req(buf);
+async fn parse_request<P: AsyncReadExt + AsyncWriteExt + Unpin>(
+    conn: &mut P,
+    mut config: Config,
+    peer_addr: Option<ConnectionAddr>,
+    local_addr: Option<ConnectionAddr>,
+) {
+    let mut buf_reader = BufReader::new(conn);
+    let request = build_and_parse_req(&mut buf_reader, &config).await;
+    let conn = buf_reader.get_mut();
 
     if let Err(e) = request {
-        let specific_err = match e {
-            RequestError::StatusLineErr => b"failed to parse status line".to_vec(),
-            RequestError::HeadersErr => b"failed to parse headers".to_vec(),
-        };
-        Response::new()
-            .mime("text/plain")
-            .body(specific_err)
-            .send(conn)
-            .await;
+        #[cfg(feature = "log")]
+        log::debug!("rejecting malformed request: {}", e);
+
+        let mut response = Response::from(e);
+        response.set_content_type();
+        response.set_framing(false, false, false);
+        response.send(conn).await;
         return;
     }
 
@@ -305,51 +268,50 @@ async fn parse_request<P: AsyncReadExt + AsyncWriteExt + Unpin>(conn: &mut P, mu
         req_middleware.lock().unwrap()(&mut request);
     };
 
-    let response = Rc::new(RefCell::new(build_res(&mut request, &mut config).await));
+    let supports_chunked = request
+        .get_status_line()
+        .get(2)
+        .is_some_and(|v| v == "HTTP/1.1");
+    let is_head = request.get_status_line()[0] == "HEAD";
 
-    let mut res_brw = response.borrow_mut();
-    let mime = res_brw.mime.clone().unwrap();
+    // Handlers are synchronous, so they get an in-memory connection, and
+    // anything they write to it is sent on ahead of the response
+    let mut handler_conn = MemoryConnection::default().addrs(peer_addr, local_addr);
+    let mut response = build_res(&mut request, &mut config, &mut handler_conn).await;
 
-    let inferred_mime = if let Some(mime_inferred) = infer::get(res_brw.body.as_ref().unwrap()) {
-        mime_inferred.mime_type().to_string()
-    } else {
-        mime
-    };
+    if !handler_conn.output.is_empty() && conn.write_all(&handler_conn.output).await.is_err() {
+        return;
+    }
+    if response.manual_override {
+        return;
+    }
+
+    response.set_content_type();
 
     if let Some(config_headers) = config.get_headers() {
-        res_brw.headers.extend(
+        response.headers.extend(
             config_headers
                 .iter()
                 .map(|(i, j)| (i.to_owned(), j.to_owned())),
         );
-        //        for (i, j) in config_headers.iter() {
-        //            res_brw.headers.insert(i.to_string(), j.to_string());
-        //        }
     }
 
-    res_brw
-        .headers
-        .insert("Content-Type: ".to_string(), inferred_mime + "\r\n");
-
-    res_brw
-        .headers
-        .insert("X-:)-->: ".to_string(), "HEHEHE\r\n".to_string());
-
-    let req_headers = request.get_headers();
+    // Connections are served one request at a time, so always close them
+    response.set_framing(false, supports_chunked, is_head);
 
     #[cfg(feature = "log")]
     {
         log::trace!(
             "RESPONSE BODY: {:#?},\n RESPONSE HEADERS: {:#?}\n",
-            res_brw.body.as_ref().unwrap(),
-            res_brw.headers,
+            response.body,
+            response.headers,
         );
     }
 
-//    #[cfg(feature = "middleware")]
-//    if let Some(middleware) = config.get_response_middleware() {
-//        middleware.lock().unwrap()(res_brw.deref_mut());
-//    }
+    //    #[cfg(feature = "middleware")]
+    //    if let Some(middleware) = config.get_response_middleware() {
+    //        middleware.lock().unwrap()(&mut response);
+    //    }
 
-    res_brw.send(conn).await;
+    response.send(conn).await;
 }
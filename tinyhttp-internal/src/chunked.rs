@@ -0,0 +1,222 @@
+use crate::{
+    headers::HeaderMap,
+    parser::{parse_header_line, read_err},
+    request::RequestError,
+};
+
+/// Longest chunk-size line that will be accepted
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// What a chunked body may use of the request limits
+pub(crate) struct ChunkedLimits {
+    pub max_body_bytes: usize,
+    /// Fields the trailer section may add, on top of those in the header section
+    pub max_trailer_count: usize,
+    /// Bytes the trailer section may use, on top of those used by the header section
+    pub max_trailer_bytes: usize,
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body from `reader`.
+///
+/// Chunk extensions are ignored, and any trailer fields are merged into `headers`.
+/// Trailer fields count against the same limits as header fields.
+#[cfg(not(feature = "async"))]
+pub(crate) fn decode_chunked<R: std::io::BufRead>(
+    reader: &mut R,
+    headers: &mut HeaderMap,
+    limits: &ChunkedLimits,
+) -> Result<Vec<u8>, RequestError> {
+    use crate::parser::read_line_limited;
+    use std::io::Read;
+
+    let read_line = |reader: &mut R| {
+        read_line_limited(
+            reader,
+            MAX_CHUNK_LINE,
+            RequestError::ChunkedBodyErr,
+            RequestError::ChunkedBodyErr,
+        )
+    };
+
+    let mut body = Vec::new();
+
+    loop {
+        let size = parse_chunk_size(&read_line(reader)?)?;
+        if size == 0 {
+            break;
+        }
+
+        if size > limits.max_body_bytes - body.len() {
+            return Err(RequestError::PayloadTooLarge);
+        }
+
+        let read = reader
+            .by_ref()
+            .take(size as u64)
+            .read_to_end(&mut body)
+            .map_err(|e| read_err(e, RequestError::ChunkedBodyErr))?;
+        if read != size {
+            return Err(RequestError::ChunkedBodyErr);
+        }
+
+        if !read_line(reader)?.is_empty() {
+            return Err(RequestError::ChunkedBodyErr);
+        }
+    }
+
+    // trailer-section, terminated by an empty line
+    let mut trailer_count = 0;
+    let mut trailer_bytes = 0;
+    loop {
+        let trailer = read_line_limited(
+            reader,
+            limits.max_trailer_bytes - trailer_bytes,
+            RequestError::HeadersTooLarge,
+            RequestError::ChunkedBodyErr,
+        )?;
+        if trailer.is_empty() {
+            break;
+        }
+
+        trailer_count += 1;
+        trailer_bytes += trailer.len();
+        if trailer_count > limits.max_trailer_count {
+            return Err(RequestError::TooManyHeaders);
+        }
+
+        add_trailer(&trailer, headers)?;
+    }
+
+    Ok(body)
+}
+
+/// Async version of [`decode_chunked`]
+#[cfg(feature = "async")]
+pub(crate) async fn decode_chunked<R: tokio::io::AsyncBufRead + Unpin>(
+    reader: &mut R,
+    headers: &mut HeaderMap,
+    limits: &ChunkedLimits,
+) -> Result<Vec<u8>, RequestError> {
+    use crate::async_http::read_line_limited;
+    use tokio::io::AsyncReadExt;
+
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line_limited(
+            reader,
+            MAX_CHUNK_LINE,
+            RequestError::ChunkedBodyErr,
+            RequestError::ChunkedBodyErr,
+        )
+        .await?;
+        let size = parse_chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+
+        if size > limits.max_body_bytes - body.len() {
+            return Err(RequestError::PayloadTooLarge);
+        }
+
+        let read = (&mut *reader)
+            .take(size as u64)
+            .read_to_end(&mut body)
+            .await
+            .map_err(|e| read_err(e, RequestError::ChunkedBodyErr))?;
+        if read != size {
+            return Err(RequestError::ChunkedBodyErr);
+        }
+
+        let line = read_line_limited(
+            reader,
+            MAX_CHUNK_LINE,
+            RequestError::ChunkedBodyErr,
+            RequestError::ChunkedBodyErr,
+        )
+        .await?;
+        if !line.is_empty() {
+            return Err(RequestError::ChunkedBodyErr);
+        }
+    }
+
+    // trailer-section, terminated by an empty line
+    let mut trailer_count = 0;
+    let mut trailer_bytes = 0;
+    loop {
+        let trailer = read_line_limited(
+            reader,
+            limits.max_trailer_bytes - trailer_bytes,
+            RequestError::HeadersTooLarge,
+            RequestError::ChunkedBodyErr,
+        )
+        .await?;
+        if trailer.is_empty() {
+            break;
+        }
+
+        trailer_count += 1;
+        trailer_bytes += trailer.len();
+        if trailer_count > limits.max_trailer_count {
+            return Err(RequestError::TooManyHeaders);
+        }
+
+        add_trailer(&trailer, headers)?;
+    }
+
+    Ok(body)
+}
+
+/// Parses `chunk-size [ ; chunk-ext ]`, ignoring any extensions
+fn parse_chunk_size(line: &[u8]) -> Result<usize, RequestError> {
+    let line = std::str::from_utf8(line).map_err(|_| RequestError::ChunkedBodyErr)?;
+    let size_str = line.split(';').next().unwrap_or_default().trim();
+    // `from_str_radix` also takes a leading `+`
+    if size_str.is_empty() || !size_str.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(RequestError::ChunkedBodyErr);
+    }
+    let size = usize::from_str_radix(size_str, 16).map_err(|_| RequestError::ChunkedBodyErr)?;
+
+    #[cfg(feature = "log")]
+    log::trace!("chunk size: {}", size);
+
+    Ok(size)
+}
+
+/// Fields a trailer may not set, since the request was already framed, routed and
+/// authenticated using the header section (RFC 9110 6.5.1)
+const FORBIDDEN_TRAILERS: &[&str] = &[
+    "Authorization",
+    "Connection",
+    "Content-Encoding",
+    "Content-Length",
+    "Content-Range",
+    "Content-Type",
+    "Cookie",
+    "Expect",
+    "Host",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+];
+
+/// Merges a trailer field into `headers`, dropping forbidden fields and never replacing
+/// a field from the header section
+fn add_trailer(trailer: &[u8], headers: &mut HeaderMap) -> Result<(), RequestError> {
+    let (key, val) = parse_header_line(trailer).map_err(|_| RequestError::ChunkedBodyErr)?;
+
+    if FORBIDDEN_TRAILERS
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(key))
+        || headers.contains(key)
+    {
+        #[cfg(feature = "log")]
+        log::debug!("ignoring trailer field {:?}", key);
+
+        return Ok(());
+    }
+
+    headers.set(key, val);
+    Ok(())
+}
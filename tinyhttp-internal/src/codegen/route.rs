@@ -2,13 +2,16 @@ use crate::config::{Method, Route, ToResponse};
 use crate::request::Request;
 use crate::response::Response;
 
+use crate::connection::Connection;
 #[cfg(test)]
 use std::any::Any;
-use std::net::TcpStream;
+use std::sync::Arc;
 
+/// A route whose handler takes no arguments, for any method (GET by default)
 #[derive(Clone, Debug)]
-pub struct BasicGetRoute {
+pub struct BasicRoute {
     path: Option<&'static str>,
+    name: Option<&'static str>,
     method: Method,
     wildcard: Option<String>,
     is_args: Option<bool>,
@@ -19,10 +22,11 @@ pub struct BasicGetRoute {
     is_ret_res: bool,
 }
 
-impl Default for BasicGetRoute {
+impl Default for BasicRoute {
     fn default() -> Self {
-        BasicGetRoute {
+        BasicRoute {
             path: None,
+            name: None,
             method: Method::GET,
             wildcard: None,
             is_args: None,
@@ -34,20 +38,24 @@ impl Default for BasicGetRoute {
     }
 }
 
-impl ToResponse for BasicGetRoute {
-    fn to_res(&self, _res: Request, _sock: &mut TcpStream) -> Response {
+impl ToResponse for BasicRoute {
+    fn to_res(&self, _res: Request, _conn: &mut dyn Connection) -> Response {
         self.get_body.unwrap()()
     }
 }
 
-impl BasicGetRoute {
-    pub fn new() -> BasicGetRoute {
+impl BasicRoute {
+    pub fn new() -> BasicRoute {
         Default::default()
     }
     pub fn set_path(mut self, path: &'static str) -> Self {
         self.path = Some(path);
         self
     }
+    pub fn set_name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
     pub fn set_method(mut self, method: Method) -> Self {
         self.method = method;
         self
@@ -78,10 +86,13 @@ impl BasicGetRoute {
     }
 }
 
-impl Route for BasicGetRoute {
+impl Route for BasicRoute {
     fn get_path(&self) -> &str {
         self.path.unwrap()
     }
+    fn get_name(&self) -> Option<&str> {
+        self.name
+    }
     fn get_method(&self) -> Method {
         self.method
     }
@@ -98,9 +109,13 @@ impl Route for BasicGetRoute {
     }
 }
 
+#[deprecated(note = "renamed to `BasicRoute`, since it serves any method")]
+pub type BasicGetRoute = BasicRoute;
+
 #[derive(Clone, Debug)]
 pub struct GetRouteWithReq {
     path: Option<&'static str>,
+    name: Option<&'static str>,
     method: Method,
     wildcard: Option<String>,
     get_body: Option<fn(Request) -> Vec<u8>>,
@@ -110,6 +125,7 @@ impl Default for GetRouteWithReq {
     fn default() -> Self {
         GetRouteWithReq {
             path: None,
+            name: None,
             method: Method::GET,
             wildcard: None,
             get_body: None,
@@ -118,7 +134,7 @@ impl Default for GetRouteWithReq {
 }
 
 impl ToResponse for GetRouteWithReq {
-    fn to_res(&self, res: Request, _sock: &mut TcpStream) -> Response {
+    fn to_res(&self, res: Request, _conn: &mut dyn Connection) -> Response {
         Response::new()
             .body(self.get_body().unwrap()(res))
             .status_line("HTTP/1.1 200 OK\r\n")
@@ -134,6 +150,10 @@ impl GetRouteWithReq {
         self.path = Some(path);
         self
     }
+    pub fn set_name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
     pub fn set_method(mut self, method: Method) -> Self {
         self.method = method;
         self
@@ -162,6 +182,9 @@ impl Route for GetRouteWithReq {
     fn get_path(&self) -> &str {
         self.path.unwrap()
     }
+    fn get_name(&self) -> Option<&str> {
+        self.name
+    }
     fn wildcard(&self) -> Option<String> {
         self.wildcard.clone()
     }
@@ -171,18 +194,21 @@ impl Route for GetRouteWithReq {
     }
 }
 
+/// A route whose handler gets the request and the connection, for any method (GET by default)
 #[derive(Clone, Debug)]
-pub struct GetRouteWithReqAndRes {
+pub struct RouteWithReqAndRes {
     path: Option<&'static str>,
+    name: Option<&'static str>,
     method: Method,
     wildcard: Option<String>,
-    get_body: Option<fn(&mut Request, &mut TcpStream) -> Response>,
+    get_body: Option<fn(&mut Request, &mut dyn Connection) -> Response>,
 }
 
-impl Default for GetRouteWithReqAndRes {
+impl Default for RouteWithReqAndRes {
     fn default() -> Self {
-        GetRouteWithReqAndRes {
+        RouteWithReqAndRes {
             path: None,
+            name: None,
             method: Method::GET,
             wildcard: None,
             get_body: None,
@@ -190,14 +216,18 @@ impl Default for GetRouteWithReqAndRes {
     }
 }
 
-impl GetRouteWithReqAndRes {
-    pub fn new() -> GetRouteWithReqAndRes {
+impl RouteWithReqAndRes {
+    pub fn new() -> RouteWithReqAndRes {
         Default::default()
     }
     pub fn set_path(mut self, path: &'static str) -> Self {
         self.path = Some(path);
         self
     }
+    pub fn set_name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
     pub fn set_method(mut self, method: Method) -> Self {
         self.method = method;
         self
@@ -206,23 +236,26 @@ impl GetRouteWithReqAndRes {
         self.wildcard = Some(wildcard);
         self
     }
-    pub fn set_body(mut self, body: fn(&'_ mut Request, &'_ mut TcpStream) -> Response) -> Self {
+    pub fn set_body(
+        mut self,
+        body: fn(&'_ mut Request, &'_ mut dyn Connection) -> Response,
+    ) -> Self {
         self.get_body = Some(body);
         self
     }
 
-    pub fn get_body(&self) -> Option<fn(&'_ mut Request, &'_ mut TcpStream) -> Response> {
+    pub fn get_body(&self) -> Option<fn(&'_ mut Request, &'_ mut dyn Connection) -> Response> {
         self.get_body
     }
 }
 
-impl ToResponse for GetRouteWithReqAndRes {
-    fn to_res(&self, mut req: Request, sock: &mut TcpStream) -> Response {
-        self.get_body().unwrap()(&mut req, sock)
+impl ToResponse for RouteWithReqAndRes {
+    fn to_res(&self, mut req: Request, conn: &mut dyn Connection) -> Response {
+        self.get_body().unwrap()(&mut req, conn)
     }
 }
 
-impl Route for GetRouteWithReqAndRes {
+impl Route for RouteWithReqAndRes {
     fn clone_dyn(&self) -> Box<dyn Route> {
         Box::new(self.clone())
     }
@@ -232,6 +265,9 @@ impl Route for GetRouteWithReqAndRes {
     fn get_path(&self) -> &str {
         self.path.unwrap()
     }
+    fn get_name(&self) -> Option<&str> {
+        self.name
+    }
     fn wildcard(&self) -> Option<String> {
         self.wildcard.clone()
     }
@@ -241,9 +277,13 @@ impl Route for GetRouteWithReqAndRes {
     }
 }
 
+#[deprecated(note = "renamed to `RouteWithReqAndRes`, since it serves any method")]
+pub type GetRouteWithReqAndRes = RouteWithReqAndRes;
+
 #[derive(Clone, Debug)]
 pub struct BasicPostRoute {
     path: Option<&'static str>,
+    name: Option<&'static str>,
     method: Method,
     wildcard: Option<String>,
     is_args: Option<bool>,
@@ -257,6 +297,7 @@ impl Default for BasicPostRoute {
     fn default() -> Self {
         BasicPostRoute {
             path: None,
+            name: None,
             method: Method::POST,
             wildcard: None,
             is_args: None,
@@ -276,6 +317,10 @@ impl BasicPostRoute {
         self.path = Some(path);
         self
     }
+    pub fn set_name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
     pub fn set_method(mut self, method: Method) -> Self {
         self.method = method;
         self
@@ -307,7 +352,7 @@ impl BasicPostRoute {
 }
 
 impl ToResponse for BasicPostRoute {
-    fn to_res(&self, _req: Request, _sock: &mut TcpStream) -> Response {
+    fn to_res(&self, _req: Request, _conn: &mut dyn Connection) -> Response {
         self.post_body.unwrap()()
     }
 }
@@ -316,6 +361,9 @@ impl Route for BasicPostRoute {
     fn get_path(&self) -> &str {
         self.path.unwrap()
     }
+    fn get_name(&self) -> Option<&str> {
+        self.name
+    }
     fn get_method(&self) -> Method {
         self.method
     }
@@ -334,6 +382,7 @@ impl Route for BasicPostRoute {
 #[derive(Clone, Debug)]
 pub struct PostRouteWithReq {
     path: Option<&'static str>,
+    name: Option<&'static str>,
     method: Method,
     wildcard: Option<String>,
     post_body: Option<fn(Request) -> Vec<u8>>,
@@ -342,6 +391,7 @@ impl Default for PostRouteWithReq {
     fn default() -> Self {
         PostRouteWithReq {
             path: None,
+            name: None,
             method: Method::POST,
             wildcard: None,
             post_body: None,
@@ -357,6 +407,10 @@ impl PostRouteWithReq {
         self.path = Some(path);
         self
     }
+    pub fn set_name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
     pub fn set_method(mut self, method: Method) -> Self {
         self.method = method;
         self
@@ -372,7 +426,7 @@ impl PostRouteWithReq {
     }
 }
 impl ToResponse for PostRouteWithReq {
-    fn to_res(&self, req: Request, _sock: &mut TcpStream) -> Response {
+    fn to_res(&self, req: Request, _conn: &mut dyn Connection) -> Response {
         Response::new()
             .body(self.post_body.unwrap()(req))
             .mime("text/plain")
@@ -390,6 +444,9 @@ impl Route for PostRouteWithReq {
     fn get_path(&self) -> &str {
         self.path.unwrap()
     }
+    fn get_name(&self) -> Option<&str> {
+        self.name
+    }
     fn wildcard(&self) -> Option<String> {
         self.wildcard.clone()
     }
@@ -402,9 +459,10 @@ impl Route for PostRouteWithReq {
 #[derive(Clone)]
 pub struct PostRouteWithReqAndRes {
     path: Option<&'static str>,
+    name: Option<&'static str>,
     method: Method,
     wildcard: Option<String>,
-    post_body: Option<fn(&mut Request, &mut TcpStream) -> Response>,
+    post_body: Option<fn(&mut Request, &mut dyn Connection) -> Response>,
 }
 
 unsafe impl Sync for PostRouteWithReqAndRes {}
@@ -414,6 +472,7 @@ impl Default for PostRouteWithReqAndRes {
     fn default() -> Self {
         PostRouteWithReqAndRes {
             path: None,
+            name: None,
             method: Method::POST,
             wildcard: None,
             post_body: None,
@@ -429,6 +488,10 @@ impl PostRouteWithReqAndRes {
         self.path = Some(path);
         self
     }
+    pub fn set_name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
     pub fn set_method(mut self, method: Method) -> Self {
         self.method = method;
         self
@@ -438,14 +501,17 @@ impl PostRouteWithReqAndRes {
         self
     }
 
-    pub fn set_body(mut self, body: fn(&'_ mut Request, &'_ mut TcpStream) -> Response) -> Self {
+    pub fn set_body(
+        mut self,
+        body: fn(&'_ mut Request, &'_ mut dyn Connection) -> Response,
+    ) -> Self {
         self.post_body = Some(body);
         self
     }
 }
 impl ToResponse for PostRouteWithReqAndRes {
-    fn to_res(&self, mut req: Request, sock: &mut TcpStream) -> Response {
-        self.post_body.unwrap()(&mut req, sock)
+    fn to_res(&self, mut req: Request, conn: &mut dyn Connection) -> Response {
+        self.post_body.unwrap()(&mut req, conn)
     }
 }
 
@@ -459,6 +525,9 @@ impl Route for PostRouteWithReqAndRes {
     fn get_path(&self) -> &str {
         self.path.unwrap()
     }
+    fn get_name(&self) -> Option<&str> {
+        self.name
+    }
     fn wildcard(&self) -> Option<String> {
         self.wildcard.clone()
     }
@@ -467,3 +536,67 @@ impl Route for PostRouteWithReqAndRes {
         self
     }
 }
+
+type ClosureHandler = Arc<dyn Fn(Request) -> Response + Send + Sync>;
+
+/// A route whose handler is a closure, so it can capture state like a connection pool
+///
+/// Usually added with `Config::route` or `Routes::route`.
+#[derive(Clone)]
+pub struct ClosureRoute {
+    path: String,
+    method: Method,
+    name: Option<String>,
+    handler: ClosureHandler,
+}
+
+impl ClosureRoute {
+    pub fn new<P, F, R>(method: Method, path: P, handler: F) -> ClosureRoute
+    where
+        P: Into<String>,
+        F: Fn(Request) -> R + Send + Sync + 'static,
+        R: Into<Response>,
+    {
+        ClosureRoute {
+            path: path.into(),
+            method,
+            name: None,
+            handler: Arc::new(move |req| handler(req).into()),
+        }
+    }
+
+    /// Names the route, so `Config::url_for` can find it
+    pub fn set_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+}
+
+impl ToResponse for ClosureRoute {
+    fn to_res(&self, req: Request, _conn: &mut dyn Connection) -> Response {
+        (self.handler)(req)
+    }
+}
+
+impl Route for ClosureRoute {
+    fn get_path(&self) -> &str {
+        &self.path
+    }
+    fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    fn get_method(&self) -> Method {
+        self.method
+    }
+    fn wildcard(&self) -> Option<String> {
+        None
+    }
+    fn clone_dyn(&self) -> Box<dyn Route> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(test)]
+    fn any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -1,24 +1,32 @@
-use std::{
-    collections::HashMap,
-    net::TcpStream,
-    ops::Deref,
-    sync::OnceLock,
+use std::{collections::HashMap, sync::OnceLock, time::Duration};
+
+use crate::{
+    codegen::route::ClosureRoute,
+    connection::Connection,
+    middleware::MiddlewareResponse,
+    request::Request,
+    router::{parse_pattern, route_pattern, RouteMatch, Router, Segment},
+    url::{percent_encode, split_target},
 };
-
-use crate::{middleware::MiddlewareResponse, request::Request};
 pub use dyn_clone::DynClone;
 use std::fmt::Debug;
 
-use crate::response::Response;
+use crate::{
+    listener::Listener,
+    response::{Response, StatusCode},
+};
 
 use rusty_pool::{Builder, ThreadPool};
+use thiserror::Error;
 
 #[cfg(not(feature = "async"))]
-use std::net::{Incoming, TcpListener};
+use crate::http::start_http;
 
 #[cfg(not(feature = "async"))]
-use crate::http::start_http;
+pub use crate::http::{ServerHandle, TestClient, TestRequest, TestServer};
 
+#[cfg(feature = "async")]
+use crate::async_http::start_http;
 
 #[cfg(test)]
 use std::any::Any;
@@ -27,24 +35,92 @@ type RouteVec = Vec<Box<dyn Route>>;
 
 type MiddlewareFn = fn(&mut Request) -> MiddlewareResponse;
 
-pub static PRE_MIDDLEWARE_CONST: OnceLock<Box<dyn FnMut(&mut Request) + Send + Sync>> =
-    OnceLock::new();
+type ErrorHandler = fn(StatusCode, &Request) -> Response;
+
+type MiddlewareClosure = Box<dyn FnMut(&mut Request) + Send + Sync>;
 
-pub static POST_MIDDLEWARE_CONST: OnceLock<Box<dyn FnMut(&mut Request) + Send + Sync>> =
-    OnceLock::new();
+pub static PRE_MIDDLEWARE_CONST: OnceLock<MiddlewareClosure> = OnceLock::new();
 
-#[derive(Clone, Copy, Debug)]
+pub static POST_MIDDLEWARE_CONST: OnceLock<MiddlewareClosure> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Method {
     GET,
     POST,
+    PUT,
+    PATCH,
+    DELETE,
+    OPTIONS,
+    HEAD,
+    CONNECT,
+    TRACE,
+}
+
+impl Method {
+    /// Every method, in the order they are listed in an `Allow` header
+    pub const ALL: [Method; 9] = [
+        Method::GET,
+        Method::HEAD,
+        Method::POST,
+        Method::PUT,
+        Method::PATCH,
+        Method::DELETE,
+        Method::OPTIONS,
+        Method::CONNECT,
+        Method::TRACE,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::GET => "GET",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::PATCH => "PATCH",
+            Method::DELETE => "DELETE",
+            Method::OPTIONS => "OPTIONS",
+            Method::HEAD => "HEAD",
+            Method::CONNECT => "CONNECT",
+            Method::TRACE => "TRACE",
+        }
+    }
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Method {
+    type Err = ();
+
+    /// Parses a request method, which is case-sensitive
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GET" => Ok(Method::GET),
+            "POST" => Ok(Method::POST),
+            "PUT" => Ok(Method::PUT),
+            "PATCH" => Ok(Method::PATCH),
+            "DELETE" => Ok(Method::DELETE),
+            "OPTIONS" => Ok(Method::OPTIONS),
+            "HEAD" => Ok(Method::HEAD),
+            "CONNECT" => Ok(Method::CONNECT),
+            "TRACE" => Ok(Method::TRACE),
+            _ => Err(()),
+        }
+    }
 }
 
 pub trait ToResponse: DynClone + Sync + Send {
-    fn to_res(&self, res: Request, sock: &mut TcpStream) -> Response;
+    fn to_res(&self, res: Request, conn: &mut dyn Connection) -> Response;
 }
 
 pub trait Route: DynClone + Sync + Send + ToResponse {
     fn get_path(&self) -> &str;
+    /// The name `Config::url_for` finds the route by
+    fn get_name(&self) -> Option<&str> {
+        None
+    }
     fn get_method(&self) -> Method;
     fn wildcard(&self) -> Option<String>;
     fn clone_dyn(&self) -> Box<dyn Route>;
@@ -60,14 +136,15 @@ impl Clone for Box<dyn Route> {
 }
 
 pub struct HttpListener {
-    pub(crate) socket: TcpListener,
+    pub(crate) socket: Listener,
     pub config: Config,
     pub pool: ThreadPool,
     pub use_pool: bool,
 }
 
 impl HttpListener {
-    pub fn new<P: Into<TcpListener>>(socket: P, config: Config) -> HttpListener {
+    /// Creates a listener from a bound TCP or Unix socket
+    pub fn new<P: Into<Listener>>(socket: P, config: Config) -> HttpListener {
         #[cfg(feature = "log")]
         log::debug!("Using {} threads", num_cpus::get());
 
@@ -79,6 +156,24 @@ impl HttpListener {
         }
     }
 
+    /// Listens on the socket passed in by systemd socket activation, or binds `addr` if
+    /// the process wasn't socket-activated
+    ///
+    /// ```ignore
+    /// let http = HttpListener::from_systemd_or_bind("[::]:8080", config)?;
+    /// http.start();
+    /// ```
+    #[cfg(unix)]
+    pub fn from_systemd_or_bind<A: std::net::ToSocketAddrs>(
+        addr: A,
+        config: Config,
+    ) -> std::io::Result<HttpListener> {
+        Ok(HttpListener::new(
+            crate::listener::from_systemd_or_bind(addr)?,
+            config,
+        ))
+    }
+
     pub fn threads(mut self, threads: usize) -> Self {
         let pool = Builder::new().core_size(threads).build();
 
@@ -97,33 +192,363 @@ impl HttpListener {
         start_http(self, conf_clone);
     }
 
+    /// Starts the server on a background thread, returning a handle that can shut it down
+    #[cfg(not(feature = "async"))]
+    pub fn spawn(self) -> std::io::Result<ServerHandle> {
+        let pool = self.use_pool.then_some(self.pool);
+        ServerHandle::spawn(vec![self.socket], std::sync::Arc::new(self.config), pool)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn start(self) {
+        start_http(self).await;
+    }
+
+    /// Accepts connections on the socket, one at a time
+    ///
+    /// This used to yield `TcpStream`s, and now yields every kind of connection a
+    /// listener can accept.
     #[cfg(not(feature = "async"))]
-    pub fn get_stream(&self) -> Incoming<'_> {
-        self.socket.incoming()
+    #[deprecated(
+        note = "use `HttpListener::start` or `HttpListener::spawn`, which serve the connections too"
+    )]
+    pub fn get_stream(&self) -> impl Iterator<Item = std::io::Result<Box<dyn Connection>>> + '_ {
+        std::iter::repeat_with(|| self.socket.accept())
+    }
+}
+
+/// Serves one [`Config`] on several sockets at once
+///
+/// Every listener shares the same worker pool and config, and one [`ServerHandle`] stops them all.
+///
+/// ```ignore
+/// let handle = Server::new(config)
+///     .listener(TcpListener::bind("0.0.0.0:8080")?)
+///     .listener(TcpListener::bind("[::]:8080")?)
+///     .listener(bind_unix("/run/tinyhttp.sock")?)
+///     .spawn()?;
+/// ```
+#[cfg(not(feature = "async"))]
+pub struct Server {
+    config: Config,
+    listeners: Vec<Listener>,
+    pool: ThreadPool,
+}
+
+#[cfg(not(feature = "async"))]
+impl Server {
+    pub fn new(config: Config) -> Server {
+        Server {
+            config,
+            listeners: Vec::new(),
+            pool: ThreadPool::default(),
+        }
+    }
+
+    /// Adds a bound TCP or Unix socket to accept connections on
+    pub fn listener<P: Into<Listener>>(mut self, socket: P) -> Self {
+        self.listeners.push(socket.into());
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.pool = Builder::new().core_size(threads).build();
+        self
+    }
+
+    /// Starts accepting on every listener, blocking until they have all stopped
+    pub fn start(self) -> std::io::Result<()> {
+        self.spawn()?.wait();
+        Ok(())
+    }
+
+    /// Starts accepting on every listener in the background
+    ///
+    /// Fails if no listeners were added.
+    pub fn spawn(self) -> std::io::Result<ServerHandle> {
+        if self.listeners.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "a server needs at least one listener",
+            ));
+        }
+
+        ServerHandle::spawn(
+            self.listeners,
+            std::sync::Arc::new(self.config),
+            Some(self.pool),
+        )
+    }
+}
+
+/// The handler for one error status, usually defined with `#[catch(404)]`
+///
+/// ### Example:
+/// ```ignore
+/// #[catch(404)]
+/// fn not_found(req: &Request) -> String {
+///     format!("nothing at {}", req.get_path())
+/// }
+///
+/// let config = Config::new().catchers(vec![not_found()]);
+/// ```
+#[derive(Clone, Copy)]
+pub struct Catcher {
+    status: StatusCode,
+    handler: fn(&Request) -> Response,
+}
+
+impl Catcher {
+    pub fn new(status: u16, handler: fn(&Request) -> Response) -> Catcher {
+        Catcher {
+            status: StatusCode(status),
+            handler,
+        }
+    }
+
+    pub fn get_status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+/// A registered route, as listed by `Config::route_table`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteInfo {
+    pub method: Method,
+    pub pattern: String,
+    /// The names of the path parameters, in the order they appear in the pattern
+    pub params: Vec<String>,
+    /// The route name, which is the handler's function name for routes defined with the macros
+    pub name: Option<String>,
+}
+
+impl RouteInfo {
+    /// Formats `table` as aligned columns, one route per line
+    pub fn to_text(table: &[RouteInfo]) -> String {
+        let width = table
+            .iter()
+            .map(|route| route.pattern.len())
+            .chain(["PATTERN".len()])
+            .max()
+            .unwrap_or_default();
+
+        let mut text = format!("{:<7} {:<width$} NAME\n", "METHOD", "PATTERN");
+        for route in table {
+            let name = route.name.as_deref().unwrap_or("-");
+            let line = format!(
+                "{:<7} {:<width$} {}",
+                route.method.as_str(),
+                route.pattern,
+                name
+            );
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Formats `table` as a JSON array of objects
+    pub fn to_json(table: &[RouteInfo]) -> String {
+        let routes: Vec<String> = table
+            .iter()
+            .map(|route| {
+                let params: Vec<String> = route.params.iter().map(|p| json_string(p)).collect();
+                let name = route
+                    .name
+                    .as_deref()
+                    .map_or("null".to_string(), json_string);
+                format!(
+                    "{{\"method\":\"{}\",\"pattern\":{},\"params\":[{}],\"name\":{}}}",
+                    route.method,
+                    json_string(&route.pattern),
+                    params.join(","),
+                    name
+                )
+            })
+            .collect();
+
+        format!("[{}]", routes.join(","))
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => json.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => json.push(c),
+        }
     }
+    json.push('"');
+    json
 }
 
+/// Why `Config::url_for` couldn't build a URL
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum UrlError {
+    #[error("no route is named `{0}`")]
+    UnknownRoute(String),
+    #[error("route `{route}` needs a value for `{param}`")]
+    MissingParam { route: String, param: String },
+}
+
+/// A group of routes
+///
+/// Groups can be merged, or nested under a path prefix, so each module of an app can
+/// own its routes and the app mounts them where it wants.
+///
+/// ### Example:
+/// ```ignore
+/// let users = Routes::new(vec![list_users(), get_user()]).middleware(vec![require_auth]);
+/// let api = Routes::new(vec![version()]).nest("/users", users);
+///
+/// let routes = Routes::new(vec![index()]).nest("/api/v1", api);
+/// // GET /api/v1/users/:id runs require_auth, GET / doesn't
+/// ```
 #[derive(Clone)]
 pub struct Routes {
     routes: RouteVec,
+    middleware: Vec<MiddlewareFn>,
 }
 
 impl Routes {
     pub fn new<R: Into<RouteVec>>(routes: R) -> Routes {
         let routes = routes.into();
-        Routes { routes }
+        Routes {
+            routes,
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Adds a route whose handler is a closure, see `Config::route`
+    pub fn route<P, F, R>(mut self, method: Method, path: P, handler: F) -> Self
+    where
+        P: Into<String>,
+        F: Fn(Request) -> R + Send + Sync + 'static,
+        R: Into<Response>,
+    {
+        self.routes
+            .push(Box::new(ClosureRoute::new(method, path, handler)));
+        self
+    }
+
+    /// Adds a named closure route, see `Config::named_route`
+    pub fn named_route<P, N, F, R>(mut self, method: Method, path: P, name: N, handler: F) -> Self
+    where
+        P: Into<String>,
+        N: Into<String>,
+        F: Fn(Request) -> R + Send + Sync + 'static,
+        R: Into<Response>,
+    {
+        let route = ClosureRoute::new(method, path, handler).set_name(name);
+        self.routes.push(Box::new(route));
+        self
+    }
+
+    /// Adds the routes of `routes` under `prefix`, keeping their own middleware
+    pub fn nest(mut self, prefix: &str, routes: Routes) -> Self {
+        self.routes.extend(routes.into_group(prefix));
+        self
+    }
+
+    /// Adds the routes of `routes`, keeping their own middleware
+    pub fn merge(self, routes: Routes) -> Self {
+        self.nest("", routes)
+    }
+
+    /// Middleware that runs before every route of this group, including ones merged or
+    /// nested into it later
+    ///
+    /// It runs in order after the middleware of any group this one is nested into, and
+    /// `MiddlewareResponse::Redirect` answers the request without calling the route.
+    pub fn middleware(mut self, middleware: Vec<MiddlewareFn>) -> Self {
+        self.middleware = middleware;
+        self
     }
 
     pub fn get_stream(self) -> RouteVec {
+        self.into_group("")
+    }
+
+    fn into_group(self, prefix: &str) -> RouteVec {
+        if prefix.is_empty() && self.middleware.is_empty() {
+            return self.routes;
+        }
+
+        let middleware = self.middleware;
         self.routes
+            .into_iter()
+            .map(|route| {
+                Box::new(GroupRoute {
+                    path: join_paths(prefix, route.get_path()),
+                    route,
+                    middleware: middleware.clone(),
+                }) as Box<dyn Route>
+            })
+            .collect()
     }
 }
 
+/// A route added to another group, with that group's prefix and middleware
+#[derive(Clone)]
+struct GroupRoute {
+    path: String,
+    route: Box<dyn Route>,
+    middleware: Vec<MiddlewareFn>,
+}
+
+impl ToResponse for GroupRoute {
+    fn to_res(&self, mut req: Request, conn: &mut dyn Connection) -> Response {
+        for middleware in &self.middleware {
+            if let MiddlewareResponse::Redirect(res) = middleware(&mut req) {
+                return res;
+            }
+        }
+
+        self.route.to_res(req, conn)
+    }
+}
+
+impl Route for GroupRoute {
+    fn get_path(&self) -> &str {
+        &self.path
+    }
+    fn get_name(&self) -> Option<&str> {
+        self.route.get_name()
+    }
+    fn get_method(&self) -> Method {
+        self.route.get_method()
+    }
+    fn wildcard(&self) -> Option<String> {
+        self.route.wildcard()
+    }
+    fn clone_dyn(&self) -> Box<dyn Route> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(test)]
+    fn any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn join_paths(prefix: &str, path: &str) -> String {
+    let segments: Vec<&str> = [prefix, path]
+        .iter()
+        .map(|part| part.trim_matches('/'))
+        .filter(|part| !part.is_empty())
+        .collect();
+
+    format!("/{}", segments.join("/"))
+}
+
 #[derive(Clone)]
 pub struct Config {
     mount_point: Option<String>,
-    get_routes: Option<HashMap<String, Box<dyn Route>>>,
-    post_routes: Option<HashMap<String, Box<dyn Route>>>,
+    routes: Router,
+    route_names: HashMap<String, String>,
     debug: bool,
     pub ssl: bool,
     ssl_chain: Option<String>,
@@ -132,7 +557,21 @@ pub struct Config {
     gzip: bool,
     spa: bool,
     http2: bool,
+    log_routes: bool,
+    routes_endpoint: Option<String>,
+    catchers: HashMap<StatusCode, fn(&Request) -> Response>,
+    error_handler: Option<ErrorHandler>,
     middleware: Option<Vec<MiddlewareFn>>,
+    keep_alive: bool,
+    keep_alive_timeout: Duration,
+    max_keep_alive_requests: usize,
+    max_request_line_len: usize,
+    max_header_count: usize,
+    max_header_bytes: usize,
+    max_body_bytes: usize,
+    header_read_timeout: Duration,
+    body_read_timeout: Duration,
+    write_timeout: Duration,
 }
 
 impl Default for Config {
@@ -160,7 +599,6 @@ impl Config {
     /// /// or
     /// let mount_config = Config::new().mount_point(".");
     /// ```
-
     pub fn new() -> Config {
         //assert!(routes.len() > 0);
 
@@ -169,8 +607,8 @@ impl Config {
 
         Config {
             mount_point: None,
-            get_routes: None,
-            post_routes: None,
+            routes: Router::default(),
+            route_names: HashMap::new(),
             debug: false,
             ssl: false,
             ssl_chain: None,
@@ -179,7 +617,21 @@ impl Config {
             gzip: false,
             spa: false,
             http2: false,
+            log_routes: false,
+            routes_endpoint: None,
+            catchers: HashMap::new(),
+            error_handler: None,
             middleware: None,
+            keep_alive: true,
+            keep_alive_timeout: Duration::from_secs(5),
+            max_keep_alive_requests: 100,
+            max_request_line_len: 8 * 1024,
+            max_header_count: 100,
+            max_header_bytes: 64 * 1024,
+            max_body_bytes: 16 * 1024 * 1024,
+            header_read_timeout: Duration::from_secs(10),
+            body_read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
         }
     }
 
@@ -190,7 +642,6 @@ impl Config {
     /// let config = Config::new().mount_point(".")
     /// /// if index.html exists in current directory, it will be returned if "/" or "/index.html" is requested.
     /// ```
-
     pub fn mount_point<P: Into<String>>(mut self, path: P) -> Self {
         self.mount_point = Some(path.into());
         self
@@ -198,6 +649,8 @@ impl Config {
 
     /// Add routes with a Route member
     ///
+    /// Calling it again adds to the routes already registered.
+    ///
     /// ### Example:
     /// ```ignore
     /// use tinyhttp::prelude::*;
@@ -222,39 +675,146 @@ impl Config {
     ///   http.start();
     /// }
     /// ```
-
     pub fn routes(mut self, routes: Routes) -> Self {
-        let mut get_routes = HashMap::new();
-        let mut post_routes = HashMap::new();
-        let routes = routes.get_stream();
+        for route in routes.get_stream() {
+            self.add_route(route);
+        }
+        self
+    }
 
-        for route in routes {
-            match route.get_method() {
-                Method::GET => {
-                    #[cfg(feature = "log")]
-                    log::info!("GET Route init!: {}", &route.get_path());
+    /// Add a route whose handler is a closure
+    ///
+    /// Unlike the macros, the handler can capture state. Routes added this way sit next to the
+    /// ones added with `routes`.
+    ///
+    /// Panics if `path` can't be matched as written, like `/files/*rest/meta` where the
+    /// catch-all isn't last, or `/:id/:id` where a parameter name repeats.
+    ///
+    /// ### Example:
+    /// ```ignore
+    /// let pool = Arc::new(DbPool::connect(url)?);
+    /// let config = Config::new()
+    ///     .routes(Routes::new(vec![index()]))
+    ///     .route(Method::GET, "/users/:id", move |req| {
+    ///         pool.find_user(req.get_param("id").unwrap())
+    ///     });
+    /// ```
+    pub fn route<P, F, R>(mut self, method: Method, path: P, handler: F) -> Self
+    where
+        P: Into<String>,
+        F: Fn(Request) -> R + Send + Sync + 'static,
+        R: Into<Response>,
+    {
+        self.add_route(Box::new(ClosureRoute::new(method, path, handler)));
+        self
+    }
 
-                    get_routes.insert(route.get_path().to_string(), route);
-                }
-                Method::POST => {
-                    #[cfg(feature = "log")]
-                    log::info!("POST Route init!: {}", &route.get_path());
-                    post_routes.insert(route.get_path().to_string(), route);
-                }
+    /// Add a closure route with a name, so `url_for` can build URLs for it
+    ///
+    /// ### Example:
+    /// ```ignore
+    /// let config = Config::new().named_route(Method::GET, "/users/:id", "user", |req| {
+    ///     format!("user {}", req.get_param("id").unwrap())
+    /// });
+    /// assert_eq!(config.url_for("user", &[("id", "7")]).unwrap(), "/users/7");
+    /// ```
+    pub fn named_route<P, N, F, R>(mut self, method: Method, path: P, name: N, handler: F) -> Self
+    where
+        P: Into<String>,
+        N: Into<String>,
+        F: Fn(Request) -> R + Send + Sync + 'static,
+        R: Into<Response>,
+    {
+        let route = ClosureRoute::new(method, path, handler).set_name(name);
+        self.add_route(Box::new(route));
+        self
+    }
+
+    fn add_route(&mut self, route: Box<dyn Route>) {
+        #[cfg(feature = "log")]
+        log::info!("{} Route init!: {}", route.get_method(), &route.get_path());
+
+        if let Some(name) = route.get_name() {
+            let pattern = route_pattern(route.as_ref());
+            let _previous = self.route_names.insert(name.to_string(), pattern);
+
+            #[cfg(feature = "log")]
+            if let Some(previous) = _previous {
+                log::warn!(
+                    "route name {} was already used for {}, it now names {}",
+                    name,
+                    previous,
+                    route.get_path()
+                );
             }
         }
-        if !get_routes.is_empty() {
-            self.get_routes = Some(get_routes);
-        } else {
-            self.get_routes = None;
+
+        let _replaced = self.routes.insert(route);
+
+        #[cfg(feature = "log")]
+        if let Some(replaced) = _replaced {
+            log::warn!(
+                "{} {} is registered more than once, only the last route is used",
+                replaced.get_method(),
+                route_pattern(replaced.as_ref())
+            );
         }
+    }
 
-        if !post_routes.is_empty() {
-            self.post_routes = Some(post_routes);
-        } else {
-            self.post_routes = None;
+    /// Responds to requests that no route or file matches
+    ///
+    /// The response gets a 404 status unless the handler sets another one.
+    pub fn not_found(self, handler: fn(&Request) -> Response) -> Self {
+        self.catchers(vec![Catcher::new(404, handler)])
+    }
+
+    /// Responds to requests whose path only has routes for other methods
+    ///
+    /// The response gets a 405 status unless the handler sets another one, and the `Allow`
+    /// header is still added.
+    pub fn method_not_allowed(self, handler: fn(&Request) -> Response) -> Self {
+        self.catchers(vec![Catcher::new(405, handler)])
+    }
+
+    /// Adds handlers for specific error statuses, replacing earlier ones for the same status
+    pub fn catchers(mut self, catchers: Vec<Catcher>) -> Self {
+        for catcher in catchers {
+            self.catchers.insert(catcher.status, catcher.handler);
         }
+        self
+    }
 
+    /// Responds to every error status that has no catcher
+    ///
+    /// Besides the server's own 400, 404, 405 and 501 responses, this also fills in the body of
+    /// error responses a route returns without one, such as the 404 from returning `()`.
+    ///
+    /// ### Example:
+    /// ```ignore
+    /// fn json_error(status: StatusCode, _req: &Request) -> Response {
+    ///     let body = format!("{{\"error\":{}}}", status.as_u16());
+    ///     Response::new().body(body.into_bytes()).mime("application/json")
+    /// }
+    ///
+    /// let config = Config::new().error_handler(json_error);
+    /// ```
+    pub fn error_handler(mut self, handler: ErrorHandler) -> Self {
+        self.error_handler = Some(handler);
+        self
+    }
+
+    /// Logs the route table when the server starts
+    pub fn log_routes(mut self, log_routes: bool) -> Self {
+        self.log_routes = log_routes;
+        self
+    }
+
+    /// Serves the route table at `path`, for debugging
+    ///
+    /// The table is plain text, or JSON when requested with `?format=json` or an
+    /// `Accept: application/json` header. Don't enable this where the routes should stay private.
+    pub fn routes_endpoint<P: Into<String>>(mut self, path: P) -> Self {
+        self.routes_endpoint = Some(path.into());
         self
     }
 
@@ -265,7 +825,6 @@ impl Config {
     /// let config = Config::new().ssl("./fullchain.pem", "./privkey.pem");
     /// ```
     /// This will only accept HTTPS connections
-
     pub fn ssl(mut self, ssl_chain: String, ssl_priv: String) -> Self {
         self.ssl_chain = Some(ssl_chain);
         self.ssl_priv = Some(ssl_priv);
@@ -285,11 +844,9 @@ impl Config {
     pub fn headers(mut self, headers: Vec<String>) -> Self {
         let mut hash_map: HashMap<String, String> = HashMap::new();
         for i in headers {
-            let mut split = i.split_inclusive(": ");
-            hash_map.insert(
-                split.next().unwrap().to_string(),
-                split.next().unwrap().to_string() + "\r\n",
-            );
+            if let Some((key, val)) = i.split_once(':') {
+                hash_map.insert(key.trim().to_string(), val.trim().to_string());
+            }
         }
 
         self.headers = Some(hash_map);
@@ -312,6 +869,89 @@ impl Config {
         self
     }
 
+    /// Enables or disables persistent (keep-alive) connections
+    ///
+    /// Enabled by default. When disabled, every connection is closed
+    /// after a single response, regardless of the request's `Connection` header.
+    pub fn keep_alive(mut self, res: bool) -> Self {
+        self.keep_alive = res;
+        self
+    }
+
+    /// How long an idle keep-alive connection is held open while waiting
+    /// for the next request (defaults to 5 seconds)
+    ///
+    /// ```ignore
+    /// let config = Config::new().keep_alive_timeout(Duration::from_secs(30));
+    /// ```
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Maximum number of requests served on a single connection before it is closed
+    /// (defaults to 100)
+    pub fn max_keep_alive_requests(mut self, max: usize) -> Self {
+        self.max_keep_alive_requests = max;
+        self
+    }
+
+    /// Longest request line (method, target and version) that will be accepted,
+    /// longer ones get a `414 URI Too Long` (defaults to 8 KiB)
+    pub fn max_request_line_len(mut self, max: usize) -> Self {
+        self.max_request_line_len = max;
+        self
+    }
+
+    /// Maximum number of headers in a request, requests with more get a
+    /// `431 Request Header Fields Too Large` (defaults to 100)
+    pub fn max_header_count(mut self, max: usize) -> Self {
+        self.max_header_count = max;
+        self
+    }
+
+    /// Maximum size of all request headers combined, larger header blocks get a
+    /// `431 Request Header Fields Too Large` (defaults to 64 KiB)
+    pub fn max_header_bytes(mut self, max: usize) -> Self {
+        self.max_header_bytes = max;
+        self
+    }
+
+    /// Maximum size of a request body, larger bodies get a `413 Payload Too Large`
+    /// before any of it is read (defaults to 16 MiB)
+    ///
+    /// ```ignore
+    /// let config = Config::new().max_body_bytes(1024 * 1024);
+    /// ```
+    pub fn max_body_bytes(mut self, max: usize) -> Self {
+        self.max_body_bytes = max;
+        self
+    }
+
+    /// How long a client has to send the request line and headers once it starts a request,
+    /// slower clients get a `408 Request Timeout` (defaults to 10 seconds)
+    ///
+    /// This is a deadline for the whole header block, so a client trickling in a byte at a time can't
+    /// hold a worker thread indefinitely.
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.header_read_timeout = timeout;
+        self
+    }
+
+    /// How long a client has to send the request body once the headers are read,
+    /// slower clients get a `408 Request Timeout` (defaults to 30 seconds)
+    pub fn body_read_timeout(mut self, timeout: Duration) -> Self {
+        self.body_read_timeout = timeout;
+        self
+    }
+
+    /// How long a single write to the client may block before the connection is dropped
+    /// (defaults to 30 seconds)
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
     pub fn middleware(mut self, middleware: Vec<MiddlewareFn>) -> Self {
         self.middleware = Some(middleware);
         self
@@ -327,72 +967,281 @@ impl Config {
     pub fn get_gzip(&self) -> bool {
         self.gzip
     }
+    pub fn get_keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+    pub fn get_keep_alive_timeout(&self) -> Duration {
+        self.keep_alive_timeout
+    }
+    pub fn get_max_keep_alive_requests(&self) -> usize {
+        self.max_keep_alive_requests
+    }
+    pub fn get_max_request_line_len(&self) -> usize {
+        self.max_request_line_len
+    }
+    pub fn get_max_header_count(&self) -> usize {
+        self.max_header_count
+    }
+    pub fn get_max_header_bytes(&self) -> usize {
+        self.max_header_bytes
+    }
+    pub fn get_max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+    pub fn get_header_read_timeout(&self) -> Duration {
+        self.header_read_timeout
+    }
+    pub fn get_body_read_timeout(&self) -> Duration {
+        self.body_read_timeout
+    }
+    pub fn get_write_timeout(&self) -> Duration {
+        self.write_timeout
+    }
     pub fn get_debug(&self) -> bool {
         self.debug
     }
     pub fn get_mount(&self) -> Option<&String> {
         self.mount_point.as_ref()
     }
-    pub fn get_routes(&self, req_path: &str) -> Option<&dyn Route> {
-        let req_path = if req_path.ends_with('/') && req_path.matches('/').count() > 1 {
-            let mut chars = req_path.chars();
-            chars.next_back();
-            chars.as_str()
-        } else {
-            req_path
-        };
+    /// Finds the route registered for `method` that matches `req_path`
+    ///
+    /// Any query string on `req_path` is ignored.
+    pub fn find_route(&self, method: Method, req_path: &str) -> Option<&dyn Route> {
+        self.match_route(method, req_path).map(|m| m.route)
+    }
+
+    /// Like `find_route`, but also returns the path parameters the route captured
+    pub(crate) fn match_route(&self, method: Method, req_path: &str) -> Option<RouteMatch<'_>> {
+        let req_path = split_target(req_path).0;
 
         #[cfg(feature = "log")]
-        log::trace!("get_routes -> new_path: {}", &req_path);
+        log::trace!("find_route -> method: {}, path: {}", method, req_path);
+
+        self.routes.find(method, req_path)
+    }
 
-        let routes = self.get_routes.as_ref()?;
+    /// Builds the path of the route called `name`, filling in its parameters
+    ///
+    /// Routes defined with the macros are named after their function, unless they were given
+    /// a `name`. Parameters the route doesn't have are added as a query string.
+    ///
+    /// ### Example:
+    /// ```ignore
+    /// #[get("/users/:id")]
+    /// fn get_user(id: Path<u32>) -> String { ... }
+    ///
+    /// let url = config.url_for("get_user", &[("id", "42"), ("tab", "posts")])?;
+    /// assert_eq!(url, "/users/42?tab=posts");
+    /// ```
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        let pattern = self
+            .route_names
+            .get(name)
+            .ok_or_else(|| UrlError::UnknownRoute(name.to_string()))?;
+
+        let mut used = vec![false; params.len()];
+        let mut url = String::new();
+        for segment in parse_pattern(pattern) {
+            url.push('/');
+            let (param, keep_slash) = match segment {
+                Segment::Static(segment) => {
+                    url.push_str(segment);
+                    continue;
+                }
+                Segment::Param(param) => (param, false),
+                Segment::CatchAll(param) => (param, true),
+            };
+
+            let i = params
+                .iter()
+                .position(|(key, _)| *key == param)
+                .ok_or_else(|| UrlError::MissingParam {
+                    route: name.to_string(),
+                    param: param.to_string(),
+                })?;
+            used[i] = true;
+            url.push_str(&percent_encode(params[i].1, keep_slash));
+        }
 
-        if let Some(route) = routes.get(req_path) {
-            return Some(route.deref());
+        if url.is_empty() {
+            url.push('/');
         }
 
-        if let Some((_, wildcard_route)) = routes
+        let query: Vec<String> = params
             .iter()
-            .find(|(path, route)| req_path.starts_with(*path) && route.wildcard().is_some())
-        {
-            return Some(wildcard_route.deref());
+            .zip(used)
+            .filter(|(_, used)| !used)
+            .map(|((key, value), _)| {
+                format!(
+                    "{}={}",
+                    percent_encode(key, false),
+                    percent_encode(value, false)
+                )
+            })
+            .collect();
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query.join("&"));
         }
 
-        None
+        Ok(url)
+    }
+
+    /// Lists the methods that have a route matching `req_path`
+    ///
+    /// `HEAD` is included whenever `GET` is, since HEAD requests fall back to the GET route.
+    pub fn allowed_methods(&self, req_path: &str) -> Vec<Method> {
+        let has_get = self.find_route(Method::GET, req_path).is_some();
+
+        Method::ALL
+            .into_iter()
+            .filter(|method| {
+                (*method == Method::HEAD && has_get) || self.find_route(*method, req_path).is_some()
+            })
+            .collect()
+    }
+
+    pub fn get_routes(&self, req_path: &str) -> Option<&dyn Route> {
+        self.find_route(Method::GET, req_path)
     }
 
     pub fn post_routes(&self, req_path: &str) -> Option<&dyn Route> {
-        #[cfg(feature = "log")]
-        log::trace!("post_routes -> path: {}", req_path);
+        self.find_route(Method::POST, req_path)
+    }
 
-        let req_path = if req_path.ends_with('/') && req_path.matches('/').count() > 1 {
-            let mut chars = req_path.chars();
-            chars.next_back();
-            chars.as_str()
-        } else {
-            req_path
-        };
+    pub fn get_spa(&self) -> bool {
+        self.spa
+    }
 
-        #[cfg(feature = "log")]
-        log::trace!("get_routes -> new_path: {}", &req_path);
+    pub(crate) fn has_error_handlers(&self) -> bool {
+        !self.catchers.is_empty() || self.error_handler.is_some()
+    }
+
+    /// Builds the response for `status`, from the catcher for it, the error handler, or the
+    /// default page, in that order
+    pub(crate) fn error_response(&self, status: StatusCode, req: &Request) -> Response {
+        let res = match (self.catchers.get(&status), self.error_handler) {
+            (Some(catcher), _) => catcher(req),
+            (None, Some(handler)) => handler(status, req),
+            (None, None) => {
+                return Response::new()
+                    .status_line(status.status_line())
+                    .body(format!("<h1>{status}</h1>").into_bytes())
+                    .mime("text/html")
+            }
+        };
 
-        let routes = self.post_routes.as_ref()?;
+        // Handlers that don't pick a status answer with the one they handle
+        if res.get_status() == Some(StatusCode::OK) {
+            res.status_line(status.status_line())
+        } else {
+            res
+        }
+    }
 
-        if let Some(route) = routes.get(req_path) {
-            return Some(route.deref());
+    /// Answers a request no route matched: 405 with an `Allow` header if `path` has routes
+    /// for other methods, and 404 otherwise
+    pub(crate) fn unrouted_response(&self, path: &str, req: &Request) -> Response {
+        let allowed = self.allowed_methods(path);
+        if allowed.is_empty() {
+            return self.error_response(StatusCode::NOT_FOUND, req);
         }
 
-        if let Some((_, wildcard_route)) = routes
+        let allow = allowed
             .iter()
-            .find(|(path, route)| req_path.starts_with(*path) && route.wildcard().is_some())
-        {
-            return Some(wildcard_route.deref());
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut response = self.error_response(StatusCode::METHOD_NOT_ALLOWED, req);
+        response.headers.insert("Allow".to_string(), allow);
+        response
+    }
+
+    /// Gives an error response from a route the body of the error handlers, if it has none
+    pub(crate) fn fill_error_response(&self, res: Response, req: &Request) -> Response {
+        let is_empty = res.body.as_ref().is_none_or(Vec::is_empty) && res.stream.is_none();
+        match res.get_status() {
+            Some(status) if status.is_error() && is_empty && self.has_error_handlers() => {
+                self.error_response(status, req)
+            }
+            _ => res,
         }
+    }
 
-        None
+    pub fn get_log_routes(&self) -> bool {
+        self.log_routes
     }
 
-    pub fn get_spa(&self) -> bool {
-        self.spa
+    pub fn get_routes_endpoint(&self) -> Option<&str> {
+        self.routes_endpoint.as_deref()
+    }
+
+    /// Lists every registered route, sorted by pattern and then method
+    pub fn route_table(&self) -> Vec<RouteInfo> {
+        let mut table: Vec<RouteInfo> = self
+            .routes
+            .routes()
+            .into_iter()
+            .map(|route| {
+                let pattern = route_pattern(route);
+                let params = parse_pattern(&pattern)
+                    .into_iter()
+                    .filter_map(|segment| match segment {
+                        Segment::Static(_) => None,
+                        Segment::Param(name) | Segment::CatchAll(name) => Some(name.to_string()),
+                    })
+                    .collect();
+
+                RouteInfo {
+                    method: route.get_method(),
+                    params,
+                    pattern,
+                    name: route.get_name().map(str::to_string),
+                }
+            })
+            .collect();
+
+        table.sort_by_key(|route| {
+            let method = Method::ALL.iter().position(|m| *m == route.method);
+            (route.pattern.clone(), method)
+        });
+        table
+    }
+
+    pub(crate) fn log_route_table(&self) {
+        #[cfg(feature = "log")]
+        if self.log_routes {
+            for line in RouteInfo::to_text(&self.route_table()).lines() {
+                log::info!("{}", line);
+            }
+        }
+    }
+
+    /// Answers a request for the route table endpoint, if that's what `req` is
+    pub(crate) fn route_table_response(&self, method: Method, req: &Request) -> Option<Response> {
+        let endpoint = self.routes_endpoint.as_deref()?;
+        let is_endpoint =
+            split_target(endpoint).0.trim_end_matches('/') == req.get_path().trim_end_matches('/');
+        if !is_endpoint || !matches!(method, Method::GET | Method::HEAD) {
+            return None;
+        }
+
+        let accepts_json = req
+            .get_headers()
+            .get("accept")
+            .is_some_and(|accept| accept.contains("application/json"));
+        let table = self.route_table();
+
+        let res = if accepts_json || req.get_query_param("format").as_deref() == Some("json") {
+            Response::new()
+                .body(RouteInfo::to_json(&table).into_bytes())
+                .mime("application/json")
+        } else {
+            Response::new()
+                .body(RouteInfo::to_text(&table).into_bytes())
+                .mime("text/plain")
+        };
+        Some(res.status_line("HTTP/1.1 200 OK\r\n"))
     }
 }
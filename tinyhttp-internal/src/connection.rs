@@ -0,0 +1,203 @@
+use std::{
+    fmt::Display,
+    io::{self, Cursor, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::PathBuf};
+
+/// The address of one end of a connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionAddr {
+    Tcp(SocketAddr),
+    /// A Unix socket, with its path if it is bound to one
+    ///
+    /// Clients connecting to a Unix socket are usually unnamed.
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+impl ConnectionAddr {
+    /// The socket address, if this is a TCP connection
+    pub fn as_socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            ConnectionAddr::Tcp(addr) => Some(*addr),
+            #[cfg(unix)]
+            ConnectionAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for ConnectionAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ConnectionAddr::Tcp(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<std::os::unix::net::SocketAddr> for ConnectionAddr {
+    fn from(addr: std::os::unix::net::SocketAddr) -> Self {
+        ConnectionAddr::Unix(addr.as_pathname().map(PathBuf::from))
+    }
+}
+
+impl Display for ConnectionAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionAddr::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            ConnectionAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            ConnectionAddr::Unix(None) => f.write_str("unix:(unnamed)"),
+        }
+    }
+}
+
+/// The transport a request is read from and its response is written to
+///
+/// Route handlers get the connection as a trait object, so they work the same over
+/// TCP, TLS, or an in-memory buffer in tests. Only `Read` and `Write` are required,
+/// the other methods default to doing nothing for transports that don't support them.
+///
+/// ```ignore
+/// impl Connection for TlsStream<TcpStream> {
+///     fn peer_addr(&self) -> io::Result<ConnectionAddr> {
+///         self.get_ref().peer_addr().map(ConnectionAddr::from)
+///     }
+/// }
+/// ```
+pub trait Connection: Read + Write + Send {
+    /// The address of the remote end
+    fn peer_addr(&self) -> io::Result<ConnectionAddr> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// The address this end of the connection is bound to
+    fn local_addr(&self) -> io::Result<ConnectionAddr> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Limits how long a single read may block, `None` blocks forever
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Limits how long a single write may block, `None` blocks forever
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Closes both halves of the connection
+    fn shutdown(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for TcpStream {
+    fn peer_addr(&self) -> io::Result<ConnectionAddr> {
+        TcpStream::peer_addr(self).map(ConnectionAddr::from)
+    }
+
+    fn local_addr(&self) -> io::Result<ConnectionAddr> {
+        TcpStream::local_addr(self).map(ConnectionAddr::from)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn peer_addr(&self) -> io::Result<ConnectionAddr> {
+        UnixStream::peer_addr(self).map(ConnectionAddr::from)
+    }
+
+    fn local_addr(&self) -> io::Result<ConnectionAddr> {
+        UnixStream::local_addr(self).map(ConnectionAddr::from)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// A connection held in memory instead of a socket
+///
+/// Reads are served from the bytes it was created with, and everything written to it
+/// is collected in `output`.
+#[derive(Debug, Default)]
+pub struct MemoryConnection {
+    input: Cursor<Vec<u8>>,
+    pub output: Vec<u8>,
+    peer_addr: Option<ConnectionAddr>,
+    local_addr: Option<ConnectionAddr>,
+}
+
+impl MemoryConnection {
+    pub fn new(input: Vec<u8>) -> MemoryConnection {
+        MemoryConnection {
+            input: Cursor::new(input),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the addresses reported by [`Connection::peer_addr`] and [`Connection::local_addr`]
+    pub fn addrs(
+        mut self,
+        peer_addr: Option<ConnectionAddr>,
+        local_addr: Option<ConnectionAddr>,
+    ) -> Self {
+        self.peer_addr = peer_addr;
+        self.local_addr = local_addr;
+        self
+    }
+}
+
+impl Read for MemoryConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MemoryConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for MemoryConnection {
+    fn peer_addr(&self) -> io::Result<ConnectionAddr> {
+        self.peer_addr
+            .clone()
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    fn local_addr(&self) -> io::Result<ConnectionAddr> {
+        self.local_addr
+            .clone()
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
@@ -1,299 +1,589 @@
 use std::{
-    io::{self, BufRead, BufReader},
-    net::TcpStream,
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
     path::Path,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use std::{fs::File, io::Read};
 
 use crate::{
-    config::{Config, HttpListener},
+    chunked::{decode_chunked, ChunkedLimits},
+    config::{Config, HttpListener, Method},
+    connection::{Connection, ConnectionAddr, MemoryConnection},
     headers::HeaderMap,
+    listener::Listener,
+    parser::{
+        add_header, body_length, parse_header_line, parse_status_line, read_err, read_line_limited,
+        BodyLength, MAX_EMPTY_LINES,
+    },
     request::{Request, RequestError},
-    response::Response,
+    response::{Response, StatusCode},
+    router::RouteMatch,
+    url::normalize_path,
 };
 
+use rusty_pool::ThreadPool;
+
 #[cfg(feature = "sys")]
 use flate2::{write::GzEncoder, Compression};
-use unicase::Ascii;
 
 pub fn start_http(http: HttpListener, config: Config) {
-    #[cfg(feature = "log")]
-    log::info!(
-        "Listening on port {}",
-        http.socket.local_addr().unwrap().port()
+    config.log_route_table();
+    let pool = http.use_pool.then_some(&http.pool);
+    serve(
+        &http.socket,
+        Arc::new(config),
+        pool,
+        &Arc::new(ShutdownState::default()),
     );
+}
+
+/// Accepts connections on `socket` until the server is asked to shut down
+///
+/// Connections are handled on `pool`, or on the accepting thread if there isn't one.
+pub(crate) fn serve(
+    socket: &Listener,
+    config: Arc<Config>,
+    pool: Option<&ThreadPool>,
+    state: &Arc<ShutdownState>,
+) {
+    #[cfg(feature = "log")]
+    if let Ok(addr) = socket.local_addr() {
+        log::info!("Listening on {}", addr);
+    }
 
-    let arc_config = Arc::new(config);
-    for stream in http.get_stream() {
-        let mut conn = stream.unwrap();
+    loop {
+        let conn = socket.accept();
+        if state.is_stopping() {
+            break;
+        }
 
-        let config = arc_config.clone();
-        if http.use_pool {
-            http.pool.execute(move || {
+        let mut conn = match conn {
+            Ok(conn) => conn,
+            Err(_e) => {
                 #[cfg(feature = "log")]
-                log::trace!("parse_request() called");
+                log::error!("failed to accept connection: {}", _e);
 
-                parse_request(&mut conn, config);
+                // Errors like running out of file descriptors don't clear up straight away
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+        };
+
+        let config = config.clone();
+        let active = state.clone().begin();
+        if let Some(pool) = pool {
+            pool.execute(move || {
+                #[cfg(feature = "log")]
+                log::trace!("serve_connection() called");
+
+                serve_connection(&mut *conn, config, &active.0);
             });
         } else {
             #[cfg(feature = "log")]
-            log::trace!("parse_request() called");
+            log::trace!("serve_connection() called");
 
-            parse_request(&mut conn, config);
+            serve_connection(&mut *conn, config, &active.0);
         }
     }
+
+    #[cfg(feature = "log")]
+    log::info!("Stopped accepting connections");
 }
 
-fn build_and_parse_req<P: Read>(conn: &mut P) -> Result<Request, RequestError> {
-    let mut buf_reader = BufReader::new(conn);
-    let mut status_line_str = String::new();
+/// Tracks whether a server is shutting down, and how many connections it is still serving
+#[derive(Default)]
+pub(crate) struct ShutdownState {
+    stopping: AtomicBool,
+    active: Mutex<usize>,
+    idle: Condvar,
+}
 
-    buf_reader.read_line(&mut status_line_str).unwrap();
-    status_line_str.drain(status_line_str.len() - 2..status_line_str.len());
+impl ShutdownState {
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
 
-    #[cfg(feature = "log")]
-    log::trace!("STATUS LINE: {:#?}", status_line_str);
+    fn begin(self: Arc<Self>) -> ActiveConnection {
+        *self.active.lock().unwrap() += 1;
+        ActiveConnection(self)
+    }
 
-    let iter = buf_reader.fill_buf().unwrap();
-    let header_end_idx = iter
-        .windows(4)
-        .position(|w| matches!(w, b"\r\n\r\n"))
-        .unwrap();
+    /// Blocks until no connections are being served, returning `false` if `deadline` passes first
+    fn wait_idle(&self, deadline: Instant) -> bool {
+        let mut active = self.active.lock().unwrap();
+        while *active > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            active = self.idle.wait_timeout(active, remaining).unwrap().0;
+        }
 
-    #[cfg(feature = "log")]
-    log::trace!("Body starts at {}", header_end_idx);
-    let headers_buf = iter[..header_end_idx + 2].to_vec();
+        true
+    }
+}
 
-    buf_reader.consume(header_end_idx + 4); // Add 4 bytes since header_end_idx does not count
-                                            // \r\n\r\n
+/// Counts a connection as in-flight until it is dropped
+struct ActiveConnection(Arc<ShutdownState>);
 
-    let mut headers = HeaderMap::new();
-    let mut headers_index = 0;
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let mut active = self.0.active.lock().unwrap();
+        *active -= 1;
+        if *active == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
 
-    let mut headers_buf_iter = headers_buf.windows(2).enumerate();
+/// Controls a server started with [`HttpListener::spawn`] or [`Server::spawn`](crate::config::Server::spawn)
+///
+/// ```ignore
+/// let handle = HttpListener::new(socket, config).spawn()?;
+/// // ...
+/// handle.shutdown(Duration::from_secs(10));
+/// ```
+pub struct ServerHandle {
+    local_addrs: Vec<ConnectionAddr>,
+    wake_addrs: Vec<WakeAddr>,
+    state: Arc<ShutdownState>,
+    stopped: mpsc::Receiver<()>,
+}
 
-    //Sort through all request headers
-    while let Some(header_index) = headers_buf_iter
-        .find(|(_, w)| matches!(*w, b"\r\n"))
-        .map(|(i, _)| i)
-    {
-        #[cfg(feature = "log")]
-        log::trace!("header index: {}", header_index);
+impl ServerHandle {
+    /// Starts an accept loop for every listener, each on its own thread
+    pub(crate) fn spawn(
+        listeners: Vec<Listener>,
+        config: Arc<Config>,
+        pool: Option<ThreadPool>,
+    ) -> io::Result<ServerHandle> {
+        let local_addrs = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<io::Result<Vec<_>>>()?;
+        let wake_addrs = listeners
+            .iter()
+            .map(WakeAddr::of)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        config.log_route_table();
+
+        let (stopped_tx, stopped) = mpsc::channel();
+        let mut handle = ServerHandle {
+            local_addrs: Vec::with_capacity(listeners.len()),
+            wake_addrs: Vec::with_capacity(listeners.len()),
+            state: Arc::new(ShutdownState::default()),
+            stopped,
+        };
+
+        let addrs = local_addrs.into_iter().zip(wake_addrs);
+        for (listener, (local_addr, wake_addr)) in listeners.into_iter().zip(addrs) {
+            let config = config.clone();
+            let pool = pool.clone();
+            let state = handle.state.clone();
+            let stopped_tx = stopped_tx.clone();
+
+            let spawned = std::thread::Builder::new()
+                .name("tinyhttp-accept".to_string())
+                .spawn(move || {
+                    serve(&listener, config, pool.as_ref(), &state);
+                    drop(listener);
+                    let _ = stopped_tx.send(());
+                });
+
+            if let Err(e) = spawned {
+                // Don't leave the listeners that did start running
+                handle.shutdown(Duration::from_secs(1));
+                return Err(e);
+            }
+            handle.local_addrs.push(local_addr);
+            handle.wake_addrs.push(wake_addr);
+        }
 
-        let header = std::str::from_utf8(&headers_buf[headers_index..header_index]).unwrap();
+        Ok(handle)
+    }
 
-        if header.is_empty() {
-            break;
+    /// The address of the first listener
+    pub fn local_addr(&self) -> &ConnectionAddr {
+        &self.local_addrs[0]
+    }
+
+    /// The addresses of every listener, in the order they were added
+    pub fn local_addrs(&self) -> &[ConnectionAddr] {
+        &self.local_addrs
+    }
+
+    /// Blocks until every listener has stopped accepting
+    pub(crate) fn wait(&self) {
+        for _ in &self.local_addrs {
+            let _ = self.stopped.recv();
         }
-        #[cfg(feature = "log")]
-        log::trace!("HEADER: {:?}", header);
+    }
 
-        headers_index = header_index + 2;
+    /// Stops accepting connections, then waits up to `timeout` for in-flight requests to finish
+    ///
+    /// Idle keep-alive connections are closed, and busy ones are closed after their current
+    /// response. Returns `false` if some requests were still running when the timeout passed.
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        self.state.stopping.store(true, Ordering::Release);
+
+        // The accept loops only notice the flag once they wake up, so poke them with a connection
+        for (_addr, wake_addr) in self.local_addrs.iter().zip(&self.wake_addrs) {
+            if let Err(_e) = wake_addr.wake(timeout) {
+                #[cfg(feature = "log")]
+                log::warn!("failed to wake the accept loop on {}: {}", _addr, _e);
+            }
+        }
 
-        let mut colon_split = header.splitn(2, ':');
-        headers.set(
-            colon_split.next().unwrap(),
-            colon_split.next().unwrap().trim(),
-        );
+        for _ in &self.local_addrs {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if self.stopped.recv_timeout(remaining).is_err() {
+                return false;
+            }
+        }
+
+        self.state.wait_idle(deadline)
     }
+}
 
-    let body_len = headers
-        .get(Ascii::new("Content-Length".to_string()))
-        .map(|str| str.parse::<usize>().unwrap())
-        .unwrap_or(0usize);
+/// Where to connect to so a listener blocked in `accept` returns
+///
+/// Unix listeners keep their full address, since one without a path (like a Linux abstract
+/// socket) can still be connected to.
+enum WakeAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::SocketAddr),
+}
 
-    let mut raw_body = vec![0; body_len];
-    buf_reader.read_exact(&mut raw_body).unwrap();
+impl WakeAddr {
+    fn of(listener: &Listener) -> io::Result<WakeAddr> {
+        Ok(match listener {
+            Listener::Tcp(listener) => WakeAddr::Tcp(listener.local_addr()?),
+            #[cfg(unix)]
+            Listener::Unix(listener) => WakeAddr::Unix(listener.local_addr()?),
+        })
+    }
 
-    Ok(Request::new(
-        raw_body,
-        headers,
-        status_line_str
-            .split_whitespace()
-            .map(|s| s.to_string())
-            .collect(),
-        None,
-    ))
+    fn wake(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            WakeAddr::Tcp(addr) => {
+                let mut addr = *addr;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                TcpStream::connect_timeout(&addr, timeout).map(drop)
+            }
+            #[cfg(unix)]
+            WakeAddr::Unix(addr) => std::os::unix::net::UnixStream::connect_addr(addr).map(drop),
+        }
+    }
 }
 
-fn build_res(mut req: Request, config: &Config, sock: &mut TcpStream) -> Response {
-    let status_line = req.get_status_line();
-    #[cfg(feature = "log")]
-    log::trace!("build_res -> req_path: {}", status_line[1]);
+/// A server on an ephemeral localhost port, for integration tests
+///
+/// The socket is listening by the time [`TestServer::start`] returns, so requests can be sent
+/// straight away. The server shuts down when this is dropped.
+///
+/// ```ignore
+/// let server = TestServer::start(Config::new().routes(routes))?;
+/// let res = minreq::get(server.url("/ping")).send()?;
+/// ```
+pub struct TestServer {
+    addr: SocketAddr,
+    handle: Option<ServerHandle>,
+}
 
-    match status_line[0].as_str() {
-        "GET" => match config.get_routes(&status_line[1]) {
-            Some(route) => {
-                #[cfg(feature = "log")]
-                log::trace!("Found path in routes!");
+impl TestServer {
+    pub fn start(config: Config) -> io::Result<TestServer> {
+        let socket = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = socket.local_addr()?;
+        let handle = HttpListener::new(socket, config).spawn()?;
 
-                if route.wildcard().is_some() {
-                    let stat_line = &status_line[1];
-                    let split = stat_line
-                        .split(&(route.get_path().to_string() + "/"))
-                        .last()
-                        .unwrap();
+        Ok(TestServer {
+            addr,
+            handle: Some(handle),
+        })
+    }
 
-                    req.set_wildcard(Some(split.into()));
-                };
+    /// The address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
 
-                route.to_res(req, sock)
-            }
+    /// Builds a URL for `path` on this server
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+}
 
-            None => match config.get_mount() {
-                Some(old_path) => {
-                    let path = old_path.to_owned() + &status_line[1];
-                    if Path::new(&path).extension().is_none() && config.get_spa() {
-                        let body = read_to_vec(old_path.to_owned() + "/index.html").unwrap();
-                        let line = "HTTP/1.1 200 OK\r\n";
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.shutdown(Duration::from_secs(5));
+        }
+    }
+}
 
-                        Response::new()
-                            .status_line(line)
-                            .body(body)
-                            .mime("text/html")
-                    } else if Path::new(&path).is_file() {
-                        let body = read_to_vec(&path).unwrap();
-                        let line = "HTTP/1.1 200 OK\r\n";
-                        let mime = mime_guess::from_path(&path)
-                            .first_raw()
-                            .unwrap_or("text/plain");
-                        Response::new().status_line(line).body(body).mime(mime)
-                    } else if Path::new(&path).is_dir() {
-                        if Path::new(&(path.to_owned() + "/index.html")).is_file() {
-                            let body = read_to_vec(path + "/index.html").unwrap();
-
-                            let line = "HTTP/1.1 200 OK\r\n";
-                            Response::new()
-                                .status_line(line)
-                                .body(body)
-                                .mime("text/html")
-                        } else {
-                            Response::new()
-                                .status_line("HTTP/1.1 200 OK\r\n")
-                                .body(b"<h1>404 Not Found</h1>".to_vec())
-                                .mime("text/html")
-                        }
-                    } else if Path::new(&(path.to_owned() + ".html")).is_file() {
-                        let body = read_to_vec(path + ".html").unwrap();
-                        let line = "HTTP/1.1 200 OK\r\n";
-                        Response::new()
-                            .status_line(line)
-                            .body(body)
-                            .mime("text/html")
-                    } else {
-                        Response::new()
-                            .status_line("HTTP/1.1 404 NOT FOUND\r\n")
-                            .body(b"<h1>404 Not Found</h1>".to_vec())
-                            .mime("text/html")
-                    }
-                }
+/// Runs requests through a [`Config`] without a server or a socket
+///
+/// Requests go through the same route matching, mount point lookup, default headers and
+/// compression as they would on a real connection, and the final response is returned.
+///
+/// ```ignore
+/// let client = TestClient::new(Config::new().routes(routes));
+/// let res = client.get("/ping");
+/// assert_eq!(res.body.unwrap(), b"pong\n");
+/// ```
+pub struct TestClient {
+    config: Config,
+}
 
-                None => Response::new()
-                    .status_line("HTTP/1.1 404 NOT FOUND\r\n")
-                    .body(b"<h1>404 Not Found</h1>".to_vec())
-                    .mime("text/html"),
-            },
-        },
-        "POST" => match config.post_routes(&status_line[1]) {
-            Some(route) => {
-                #[cfg(feature = "log")]
-                log::debug!("POST");
+impl TestClient {
+    pub fn new(config: Config) -> TestClient {
+        TestClient { config }
+    }
 
-                if route.wildcard().is_some() {
-                    let stat_line = &status_line[1];
+    pub fn send(&self, request: Request) -> Response {
+        // Anything a handler writes to the connection directly is discarded
+        let mut conn = MemoryConnection::default();
+        let keep_alive = self.config.get_keep_alive() && wants_keep_alive(&request);
 
-                    let split = stat_line
-                        .split(&(route.get_path().to_string() + "/"))
-                        .last()
-                        .unwrap();
+        respond(request, &self.config, &mut conn, keep_alive).0
+    }
 
-                    req.set_wildcard(Some(split.into()));
-                };
+    /// Sends a `GET` request for `path`, without any headers
+    pub fn get(&self, path: &str) -> Response {
+        self.request(Method::GET, path).send()
+    }
 
-                route.to_res(req, sock)
-            }
+    /// Builds a request for `path`, which is sent with [`TestRequest::send`]
+    ///
+    /// ```ignore
+    /// let res = client
+    ///     .request(Method::POST, "/echo")
+    ///     .header("Content-Type", "text/plain")
+    ///     .body("hello")
+    ///     .send();
+    /// assert_eq!(res.body_string(), "hello");
+    /// ```
+    pub fn request(&self, method: Method, path: &str) -> TestRequest<'_> {
+        TestRequest {
+            client: self,
+            status_line: vec![
+                method.as_str().to_string(),
+                path.to_string(),
+                "HTTP/1.1".to_string(),
+            ],
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        }
+    }
+}
 
-            None => Response::new()
-                .status_line("HTTP/1.1 404 NOT FOUND\r\n")
-                .body(b"<h1>404 Not Found</h1>".to_vec())
-                .mime("text/html"),
-        },
+/// A request being built by [`TestClient::request`]
+pub struct TestRequest<'a> {
+    client: &'a TestClient,
+    status_line: Vec<String>,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
 
-        _ => Response::new()
-            .status_line("HTTP/1.1 404 NOT FOUND\r\n")
-            .body(b"<h1>Unkown Error Occurred</h1>".to_vec())
-            .mime("text/html"),
+impl TestRequest<'_> {
+    pub fn header(mut self, key: &str, val: &str) -> Self {
+        self.headers.set(key, val);
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Runs the request through the client's config, returning the response
+    pub fn send(self) -> Response {
+        self.client.send(Request::new(
+            self.body,
+            self.headers,
+            self.status_line,
+            None,
+        ))
     }
 }
 
-pub fn parse_request(conn: &mut TcpStream, config: Arc<Config>) {
-    let request = build_and_parse_req(conn);
-
-    let request = match request {
-        Ok(request) => request,
-        Err(e) => {
-            let specific_err = match e {
-                RequestError::StatusLineErr => b"failed to parse status line".to_vec(),
-                RequestError::HeadersErr => b"failed to parse headers".to_vec(),
-            };
-            Response::new()
-                .mime("text/plain")
-                .body(specific_err)
-                .send(conn);
+/// Wraps a connection so reads fail once a deadline has passed.
+///
+/// A plain socket read timeout only bounds each individual read, so a client
+/// trickling in a byte at a time could otherwise hold a worker forever.
+struct TimedStream<'a> {
+    conn: &'a mut dyn Connection,
+    deadline: Option<Instant>,
+}
 
-            return;
+impl<'a> TimedStream<'a> {
+    fn new(conn: &'a mut dyn Connection) -> Self {
+        TimedStream {
+            conn,
+            deadline: None,
         }
-    };
+    }
 
-    /*#[cfg(feature = "middleware")]
-    if let Some(req_middleware) = config.get_request_middleware() {
-        req_middleware.lock().unwrap()(&mut request);
-    };*/
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.deadline = Some(Instant::now() + timeout);
+    }
 
-    let req_headers = request.get_headers();
-    let _comp = if config.get_gzip() {
-        if req_headers.contains("Accept-Encoding") {
-            let tmp_str = req_headers.get("Accept-Encoding").unwrap();
-            let res: Vec<&str> = tmp_str.split(',').map(|s| s.trim()).collect();
+    fn clear_timeout(&mut self) {
+        self.deadline = None;
+        let _ = self.conn.set_read_timeout(None);
+    }
+}
 
-            #[cfg(feature = "log")]
-            log::trace!("{:#?}", &res);
+impl Read for TimedStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.conn.set_read_timeout(Some(remaining))?;
+        }
 
-            res.contains(&"gzip")
-        } else {
-            false
+        self.conn.read(buf)
+    }
+}
+
+impl Write for TimedStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+fn build_and_parse_req(
+    buf_reader: &mut BufReader<TimedStream>,
+    config: &Config,
+) -> Result<Request, RequestError> {
+    let mut empty_lines = 0;
+    let status_line = loop {
+        let line = read_line_limited(
+            buf_reader,
+            config.get_max_request_line_len(),
+            RequestError::RequestLineTooLong,
+            RequestError::StatusLineErr,
+        )?;
+        if !line.is_empty() {
+            break line;
+        }
+
+        empty_lines += 1;
+        if empty_lines > MAX_EMPTY_LINES {
+            return Err(RequestError::StatusLineErr);
         }
-    } else {
-        false
     };
+    let status_line = parse_status_line(&status_line)?;
 
-    let mut response = build_res(request, &config, conn);
-    if response.manual_override {
-        conn.shutdown(std::net::Shutdown::Both).unwrap();
-        return;
+    let mut headers = HeaderMap::new();
+    let mut header_count = 0;
+    let mut header_bytes = 0;
+
+    //Sort through all request headers
+    loop {
+        let header = read_line_limited(
+            buf_reader,
+            config.get_max_header_bytes() - header_bytes,
+            RequestError::HeadersTooLarge,
+            RequestError::HeadersErr,
+        )?;
+        if header.is_empty() {
+            break;
+        }
+
+        header_count += 1;
+        header_bytes += header.len();
+        if header_count > config.get_max_header_count() {
+            return Err(RequestError::TooManyHeaders);
+        }
+
+        let (key, val) = parse_header_line(&header)?;
+        add_header(&mut headers, key, val)?;
     }
 
-    match response.mime {
-        Some(ref t) => {
-            response
-                .headers
-                .insert("Content-Type".to_string(), t.to_owned());
+    let body_length = body_length(&headers, config.get_max_body_bytes())?;
+    buf_reader
+        .get_mut()
+        .set_timeout(config.get_body_read_timeout());
+
+    let raw_body = match body_length {
+        BodyLength::Chunked => {
+            let limits = ChunkedLimits {
+                max_body_bytes: config.get_max_body_bytes(),
+                max_trailer_count: config.get_max_header_count() - header_count,
+                max_trailer_bytes: config.get_max_header_bytes() - header_bytes,
+            };
+            decode_chunked(buf_reader, &mut headers, &limits)?
         }
-        None => {
-            if let Some(body) = &response.body {
-                let mime = infer::get(body)
-                    .map(|mime| mime.mime_type())
-                    .unwrap_or("text/plain");
-
-                response
-                    .headers
-                    .insert("Content-Type".to_string(), mime.to_string());
-            }
+        BodyLength::Fixed(body_len) => {
+            let mut raw_body = vec![0; body_len];
+            buf_reader
+                .read_exact(&mut raw_body)
+                .map_err(|e| read_err(e, RequestError::TruncatedBody))?;
+            raw_body
         }
+    };
+
+    Ok(Request::new(raw_body, headers, status_line, None))
+}
+
+/// Turns a request into the response the server sends for it, including its
+/// default headers, compression and framing
+///
+/// Returns the response, and whether the connection can stay open afterwards.
+/// Handlers that take over the connection return a response with `manual_override` set.
+pub(crate) fn respond(
+    request: Request,
+    config: &Config,
+    conn: &mut dyn Connection,
+    keep_alive: bool,
+) -> (Response, bool) {
+    let supports_chunked = request
+        .get_status_line()
+        .get(2)
+        .is_some_and(|v| v == "HTTP/1.1");
+    let is_head = request.get_status_line()[0] == "HEAD";
+
+    /*#[cfg(feature = "middleware")]
+    if let Some(req_middleware) = config.get_request_middleware() {
+        req_middleware.lock().unwrap()(&mut request);
+    };*/
+
+    // Only check for 'accept-encoding' header
+    // when compression is enabled
+    let _comp = config.get_gzip()
+        && request
+            .get_headers()
+            .get("Accept-Encoding")
+            .is_some_and(|e| e.split(',').any(|e| e.trim() == "gzip"));
+
+    let mut response = build_res(request, config, conn);
+    if response.manual_override {
+        return (response, false);
     }
 
+    response.set_content_type();
+
     if let Some(config_headers) = config.get_headers() {
         response.headers.extend(
             config_headers
@@ -307,16 +597,24 @@ pub fn parse_request(conn: &mut TcpStream, config: Arc<Config>) {
         env!("CARGO_PKG_VERSION").to_string(),
     )]);
 
-    // Only check for 'accept-encoding' header
-    // when compression is enabled
-
     #[cfg(feature = "sys")]
     {
-        if _comp {
+        // Only compress something, so an empty response isn't labelled as gzip
+        let has_body = response.stream.is_some()
+            || response.body.as_ref().is_some_and(|body| !body.is_empty());
+        if _comp && has_body && response.allows_body() {
+            use crate::response::BodyStream;
             use std::io::Write;
-            let mut writer = GzEncoder::new(Vec::new(), Compression::default());
-            writer.write_all(response.body.as_ref().unwrap()).unwrap();
-            response.body = Some(writer.finish().unwrap());
+
+            if let Some(stream) = response.stream.take() {
+                // Compress streamed bodies as they are read
+                let encoder = flate2::read::GzEncoder::new(stream, Compression::default());
+                response.stream = Some(BodyStream::new(encoder));
+            } else if let Some(body) = &response.body {
+                let mut writer = GzEncoder::new(Vec::new(), Compression::default());
+                writer.write_all(body).unwrap();
+                response.body = Some(writer.finish().unwrap());
+            }
             response
                 .headers
                 .insert("Content-Encoding".to_string(), "gzip".to_string());
@@ -327,7 +625,7 @@ pub fn parse_request(conn: &mut TcpStream, config: Arc<Config>) {
     {
         log::trace!(
             "RESPONSE BODY: {:#?},\n RESPONSE HEADERS: {:#?}\n",
-            response.body.as_ref().unwrap(),
+            response.body,
             response.headers,
         );
     }
@@ -337,7 +635,240 @@ pub fn parse_request(conn: &mut TcpStream, config: Arc<Config>) {
         middleware.lock().unwrap()(res_brw.deref_mut());
     }*/
 
-    response.send(conn);
+    let keep_alive = response.set_framing(keep_alive, supports_chunked, is_head);
+    (response, keep_alive)
+}
+
+fn build_res(mut req: Request, config: &Config, conn: &mut dyn Connection) -> Response {
+    let req_path = req.get_path().to_owned();
+    #[cfg(feature = "log")]
+    log::trace!("build_res -> req_path: {}", req_path);
+
+    let method = match req.get_status_line()[0].parse::<Method>() {
+        Ok(method) => method,
+        Err(_) => return config.error_response(StatusCode::NOT_IMPLEMENTED, &req),
+    };
+
+    if let Some(res) = config.route_table_response(method, &req) {
+        return res;
+    }
+
+    // HEAD requests are answered by the GET route, unless one was registered for HEAD
+    let route = config
+        .match_route(method, &req_path)
+        .or_else(|| match method {
+            Method::HEAD => config.match_route(Method::GET, &req_path),
+            _ => None,
+        });
+
+    match route {
+        Some(RouteMatch { route, params }) => {
+            #[cfg(feature = "log")]
+            log::trace!("Found path in routes!");
+
+            req.set_params(params);
+
+            // Keep what error handlers need, since the route consumes the request
+            let head = config.has_error_handlers().then(|| req.without_body());
+            let res = route.to_res(req, conn);
+            match head {
+                Some(head) => config.fill_error_response(res, &head),
+                None => res,
+            }
+        }
+
+        None => match config.get_mount() {
+            Some(old_path) if matches!(method, Method::GET | Method::HEAD) => {
+                let path = match normalize_path(&req_path) {
+                    Some(file_path) => old_path.to_owned() + &file_path,
+                    None => return config.error_response(StatusCode::BAD_REQUEST, &req),
+                };
+                if Path::new(&path).extension().is_none() && config.get_spa() {
+                    let body = read_to_vec(old_path.to_owned() + "/index.html").unwrap();
+                    let line = "HTTP/1.1 200 OK\r\n";
+
+                    Response::new()
+                        .status_line(line)
+                        .body(body)
+                        .mime("text/html")
+                } else if Path::new(&path).is_file() {
+                    let body = read_to_vec(&path).unwrap();
+                    let line = "HTTP/1.1 200 OK\r\n";
+                    let mime = mime_guess::from_path(&path)
+                        .first_raw()
+                        .unwrap_or("text/plain");
+                    Response::new().status_line(line).body(body).mime(mime)
+                } else if Path::new(&path).is_dir() {
+                    if Path::new(&(path.to_owned() + "/index.html")).is_file() {
+                        let body = read_to_vec(path + "/index.html").unwrap();
+
+                        let line = "HTTP/1.1 200 OK\r\n";
+                        Response::new()
+                            .status_line(line)
+                            .body(body)
+                            .mime("text/html")
+                    } else {
+                        config.unrouted_response(&req_path, &req)
+                    }
+                } else if Path::new(&(path.to_owned() + ".html")).is_file() {
+                    let body = read_to_vec(path + ".html").unwrap();
+                    let line = "HTTP/1.1 200 OK\r\n";
+                    Response::new()
+                        .status_line(line)
+                        .body(body)
+                        .mime("text/html")
+                } else {
+                    config.unrouted_response(&req_path, &req)
+                }
+            }
+
+            _ => config.unrouted_response(&req_path, &req),
+        },
+    }
+}
+
+/// Serves every request sent on `conn`, until the connection is closed
+#[deprecated(note = "connections are served by `HttpListener::start` and `HttpListener::spawn`")]
+pub fn parse_request(conn: &mut TcpStream, config: Arc<Config>) {
+    serve_connection(conn, config, &ShutdownState::default());
+}
+
+pub(crate) fn serve_connection(
+    conn: &mut dyn Connection,
+    config: Arc<Config>,
+    state: &ShutdownState,
+) {
+    let _ = conn.set_write_timeout(Some(config.get_write_timeout()));
+
+    let mut buf_reader = BufReader::new(TimedStream::new(conn));
+    let mut served = 0usize;
+
+    loop {
+        if !wait_for_request(&mut buf_reader, &config, state) {
+            #[cfg(feature = "log")]
+            log::trace!("closing connection after {} request(s)", served);
+
+            return;
+        }
+
+        buf_reader
+            .get_mut()
+            .set_timeout(config.get_header_read_timeout());
+        let request = build_and_parse_req(&mut buf_reader, &config);
+        buf_reader.get_mut().clear_timeout();
+        served += 1;
+
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                #[cfg(feature = "log")]
+                if let RequestError::Timeout = e {
+                    log::warn!(
+                        "{:?}: timed out reading request, closing connection",
+                        buf_reader.get_ref().conn.peer_addr()
+                    );
+                } else {
+                    log::debug!("rejecting malformed request: {}", e);
+                }
+
+                // The rest of the stream can't be trusted, so always close the connection
+                let mut response = Response::from(e);
+                response.set_content_type();
+                response.set_framing(false, false, false);
+                let _ = response.write_to(buf_reader.get_mut());
+
+                return;
+            }
+        };
+
+        let keep_alive = config.get_keep_alive()
+            && !state.is_stopping()
+            && served < config.get_max_keep_alive_requests()
+            && wants_keep_alive(&request);
+        let conn = &mut *buf_reader.get_mut().conn;
+
+        let (response, keep_alive) = respond(request, &config, conn, keep_alive);
+        if response.manual_override {
+            let _ = conn.shutdown();
+            return;
+        }
+
+        if let Err(_e) = response.write_to(conn) {
+            #[cfg(feature = "log")]
+            log::warn!(
+                "{:?}: failed to write response, closing connection: {}",
+                conn.peer_addr(),
+                _e
+            );
+
+            return;
+        }
+
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Waits for the next request, giving up once the connection has been idle for
+/// longer than the keep-alive timeout, or the server starts shutting down
+fn wait_for_request(
+    buf_reader: &mut BufReader<TimedStream>,
+    config: &Config,
+    state: &ShutdownState,
+) -> bool {
+    // Wait in short slices, so idle connections notice a shutdown quickly
+    const SLICE: Duration = Duration::from_millis(100);
+
+    let deadline = Instant::now() + config.get_keep_alive_timeout();
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || state.is_stopping() {
+            return false;
+        }
+
+        buf_reader.get_mut().set_timeout(remaining.min(SLICE));
+        match buf_reader.fill_buf() {
+            Ok([]) => return false,
+            Ok(buf) => {
+                // Drop empty lines sent after the last request, so they don't keep the
+                // connection waiting on the header timeout (RFC 9112 2.2)
+                let blank = buf
+                    .iter()
+                    .take_while(|b| matches!(b, b'\r' | b'\n'))
+                    .count();
+                let skip = buf[..blank]
+                    .iter()
+                    .rposition(|b| *b == b'\n')
+                    .map_or(0, |i| i + 1);
+                let only_blank = skip == buf.len();
+                buf_reader.consume(skip);
+                if !only_blank {
+                    return true;
+                }
+            }
+            Err(e) => match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {}
+                _ => return false,
+            },
+        }
+    }
+}
+
+/// HTTP/1.1 connections are persistent unless the client sends `Connection: close`,
+/// HTTP/1.0 connections are closed unless the client sends `Connection: keep-alive`
+fn wants_keep_alive(req: &Request) -> bool {
+    let has_token = |token: &str| {
+        req.get_headers()
+            .get("Connection")
+            .map(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    };
+
+    match req.get_status_line().get(2).map(|v| v.as_str()) {
+        Some("HTTP/1.1") => !has_token("close"),
+        _ => has_token("keep-alive"),
+    }
 }
 
 fn read_to_vec<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
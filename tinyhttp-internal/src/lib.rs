@@ -1,12 +1,18 @@
 //#![warn(missing_docs)]
 #![warn(clippy::all)]
 
+mod chunked;
 pub mod codegen;
 pub mod config;
+pub mod connection;
 pub mod headers;
+pub mod listener;
 pub mod middleware;
+mod parser;
 pub mod request;
 pub mod response;
+mod router;
+mod url;
 
 #[cfg(not(feature = "async"))]
 pub mod http;
@@ -40,6 +46,48 @@ mod tests {
             "Hello, World!".to_string()
         )
     }
+    #[test]
+    fn parse_query_string() {
+        use crate::request::Request;
+
+        let request = Request::new(
+            Vec::new(),
+            HeaderMap::new(),
+            vec![
+                "GET".to_string(),
+                "/search?q=a%20b+c&tag=x&tag=y&empty&bad=%zz#frag".to_string(),
+                "HTTP/1.1".to_string(),
+            ],
+            None,
+        );
+
+        assert_eq!(request.get_path(), "/search");
+        assert_eq!(request.get_query_param("q").as_deref(), Some("a b c"));
+        assert_eq!(request.get_query_params("tag"), ["x", "y"]);
+        assert_eq!(request.get_query_param("empty").as_deref(), Some(""));
+        assert_eq!(request.get_query_param("bad").as_deref(), Some("%zz"));
+        assert_eq!(request.get_query_param("missing"), None);
+    }
+
+    #[test]
+    fn normalize_request_path() {
+        use crate::url::normalize_path;
+
+        assert_eq!(normalize_path("/a/./b//c/").as_deref(), Some("/a/b/c"));
+        assert_eq!(normalize_path("/a/b/../c").as_deref(), Some("/a/c"));
+        assert_eq!(
+            normalize_path("/my%20file.txt").as_deref(),
+            Some("/my file.txt")
+        );
+        assert_eq!(normalize_path("/a+b").as_deref(), Some("/a+b"));
+        assert_eq!(normalize_path("/").as_deref(), Some("/"));
+
+        assert_eq!(normalize_path("/../etc/passwd"), None);
+        assert_eq!(normalize_path("/a/%2e%2e/%2E%2E/etc"), None);
+        assert_eq!(normalize_path("/..%5c..%5cwin.ini"), None);
+        assert_eq!(normalize_path("/file%00.txt"), None);
+    }
+
     #[test]
     fn build_response() {
         use crate::response::Response;
@@ -53,4 +101,126 @@ mod tests {
             String::from("1 2 3 test test...")
         );
     }
+
+    #[test]
+    #[cfg(not(feature = "async"))]
+    fn decode_chunked_body() {
+        use crate::chunked::{decode_chunked, ChunkedLimits};
+        use crate::request::RequestError;
+
+        let unlimited = ChunkedLimits {
+            max_body_bytes: usize::MAX,
+            max_trailer_count: usize::MAX,
+            max_trailer_bytes: usize::MAX,
+        };
+
+        let mut headers = HeaderMap::new();
+        let mut raw: &[u8] =
+            b"5;name=value\r\nHello\r\n8\r\n, World!\r\n0\r\nX-Checksum: abc\r\n\r\n";
+
+        let body = decode_chunked(&mut raw, &mut headers, &unlimited).unwrap();
+        assert_eq!(body, b"Hello, World!");
+        assert_eq!(headers.get("x-checksum"), Some("abc"));
+        assert!(raw.is_empty());
+
+        // Trailers can't change how the request was framed, or replace its headers
+        let mut headers = HeaderMap::new();
+        headers.set("Content-Length", "5");
+        let mut raw: &[u8] = b"0\r\nContent-Length: 0\r\nhost: evil\r\n\r\n";
+        decode_chunked(&mut raw, &mut headers, &unlimited).unwrap();
+        assert_eq!(headers.get("content-length"), Some("5"));
+        assert!(!headers.contains("host"));
+
+        let mut signed: &[u8] = b"+5\r\nHello\r\n0\r\n\r\n";
+        assert!(decode_chunked(&mut signed, &mut headers, &unlimited).is_err());
+
+        let mut truncated: &[u8] = b"a\r\nshort\r\n";
+        assert!(decode_chunked(&mut truncated, &mut headers, &unlimited).is_err());
+
+        let mut too_large: &[u8] = b"5\r\nHello\r\n0\r\n\r\n";
+        assert!(matches!(
+            decode_chunked(
+                &mut too_large,
+                &mut headers,
+                &ChunkedLimits {
+                    max_body_bytes: 4,
+                    ..unlimited
+                }
+            ),
+            Err(RequestError::PayloadTooLarge)
+        ));
+
+        let mut many_trailers: &[u8] = b"0\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert!(matches!(
+            decode_chunked(
+                &mut many_trailers,
+                &mut headers,
+                &ChunkedLimits {
+                    max_trailer_count: 2,
+                    ..unlimited
+                }
+            ),
+            Err(RequestError::TooManyHeaders)
+        ));
+
+        let mut long_trailers: &[u8] = b"0\r\nA: 1234567890\r\n\r\n";
+        assert!(matches!(
+            decode_chunked(
+                &mut long_trailers,
+                &mut headers,
+                &ChunkedLimits {
+                    max_trailer_bytes: 8,
+                    ..unlimited
+                }
+            ),
+            Err(RequestError::HeadersTooLarge)
+        ));
+    }
+
+    #[cfg(all(unix, not(feature = "async")))]
+    #[test]
+    fn inherited_fds_must_be_listening_stream_sockets() {
+        use crate::listener::{from_inherited_fd, Listener};
+        use std::net::{TcpListener, TcpStream, UdpSocket};
+        use std::os::fd::{AsRawFd, IntoRawFd};
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(unsafe { from_inherited_fd(udp.as_raw_fd()) }.is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(unsafe { from_inherited_fd(stream.as_raw_fd()) }.is_err());
+
+        // std sets close-on-exec itself, unlike service managers passing sockets on
+        let fd = listener.into_raw_fd();
+        unsafe { libc::fcntl(fd, libc::F_SETFD, 0) };
+        let adopted = unsafe { from_inherited_fd(fd) }.unwrap();
+        assert!(matches!(adopted, Listener::Tcp(_)));
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+    }
+
+    #[test]
+    fn check_route_patterns() {
+        use crate::router::{check_pattern, parse_pattern};
+
+        for valid in ["/", "/users/:id/posts/:post_id", "/files/*path", "/old/:"] {
+            assert!(check_pattern(&parse_pattern(valid)).is_ok(), "{valid}");
+        }
+        for invalid in ["/files/*path/meta", "/:/users", "/users/:id/posts/:id"] {
+            assert!(check_pattern(&parse_pattern(invalid)).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn format_http_date() {
+        use crate::response::http_date;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let date = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(date), "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(http_date(leap_day), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
 }
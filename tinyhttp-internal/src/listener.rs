@@ -0,0 +1,259 @@
+use std::io;
+
+#[cfg(unix)]
+use std::path::Path;
+
+use crate::connection::ConnectionAddr;
+
+#[cfg(not(feature = "async"))]
+use crate::connection::Connection;
+
+#[cfg(not(feature = "async"))]
+use std::net::TcpListener;
+
+#[cfg(all(unix, not(feature = "async")))]
+use std::os::unix::net::UnixListener;
+
+#[cfg(feature = "async")]
+use tokio::net::TcpListener;
+
+#[cfg(all(unix, feature = "async"))]
+use tokio::net::UnixListener;
+
+/// A socket the server accepts connections on
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+impl Listener {
+    pub fn local_addr(&self) -> io::Result<ConnectionAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ConnectionAddr::from),
+            #[cfg(all(unix, not(feature = "async")))]
+            Listener::Unix(listener) => listener.local_addr().map(ConnectionAddr::from),
+            #[cfg(all(unix, feature = "async"))]
+            Listener::Unix(listener) => listener
+                .local_addr()
+                .map(|addr| ConnectionAddr::Unix(addr.as_pathname().map(Path::to_path_buf))),
+        }
+    }
+
+    /// Waits for the next connection
+    #[cfg(not(feature = "async"))]
+    pub(crate) fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(match self {
+            Listener::Tcp(listener) => Box::new(listener.accept()?.0),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Box::new(listener.accept()?.0),
+        })
+    }
+}
+
+/// The first file descriptor passed by the service manager
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Takes the sockets passed in by systemd socket activation
+///
+/// Returns `None` if the process wasn't socket-activated, i.e. `LISTEN_PID` is missing or names
+/// another process. The sockets are only returned once, by the first call.
+///
+/// The `LISTEN_*` variables are left alone, since changing the environment isn't thread-safe.
+/// Unset them before spawning threads if child processes shouldn't see them.
+#[cfg(unix)]
+pub fn from_systemd() -> io::Result<Option<Vec<Listener>>> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // A second call would wrap the same descriptors again
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+
+    let invalid = |var: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid {var}"));
+
+    let pid = match std::env::var("LISTEN_PID") {
+        Ok(pid) => pid.parse::<u32>().map_err(|_| invalid("LISTEN_PID"))?,
+        Err(_) => return Ok(None),
+    };
+    if pid != std::process::id() {
+        return Ok(None);
+    }
+
+    let fds = match std::env::var("LISTEN_FDS") {
+        Ok(fds) => fds.parse::<i32>().map_err(|_| invalid("LISTEN_FDS"))?,
+        Err(_) => return Ok(None),
+    };
+
+    let fds_end = LISTEN_FDS_START
+        .checked_add(fds)
+        .ok_or_else(|| invalid("LISTEN_FDS"))?;
+
+    if fds <= 0 || TAKEN.swap(true, Ordering::AcqRel) {
+        return Ok(None);
+    }
+
+    #[cfg(feature = "log")]
+    log::info!("using {} socket(s) from systemd", fds);
+
+    (LISTEN_FDS_START..fds_end)
+        .map(|fd| unsafe { from_inherited_fd(fd) })
+        .collect::<io::Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// Wraps an inherited listening socket, working out whether it is TCP or Unix
+///
+/// Anything but a listening stream socket is refused, since accepting on it would fail
+/// forever. The descriptor is marked close-on-exec, so it isn't leaked to child processes.
+///
+/// # Safety
+///
+/// `fd` must be an open descriptor that nothing else owns.
+#[cfg(unix)]
+pub(crate) unsafe fn from_inherited_fd(fd: i32) -> io::Result<Listener> {
+    use std::os::fd::FromRawFd;
+
+    let not_listening = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("inherited fd {fd} is not a listening TCP or Unix socket"),
+        )
+    };
+
+    let sock_opt = |opt| {
+        let mut val: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let val_ptr = std::ptr::addr_of_mut!(val).cast();
+        match libc::getsockopt(fd, libc::SOL_SOCKET, opt, val_ptr, &mut len) {
+            0 => Ok(val),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+    if sock_opt(libc::SO_TYPE)? != libc::SOCK_STREAM || sock_opt(libc::SO_ACCEPTCONN)? == 0 {
+        return Err(not_listening());
+    }
+
+    let flags = libc::fcntl(fd, libc::F_GETFD);
+    if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if libc::getsockname(fd, std::ptr::addr_of_mut!(addr).cast(), &mut len) == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match libc::c_int::from(addr.ss_family) {
+        libc::AF_INET | libc::AF_INET6 => from_std_tcp(std::net::TcpListener::from_raw_fd(fd)),
+        libc::AF_UNIX => from_std_unix(std::os::unix::net::UnixListener::from_raw_fd(fd)),
+        _ => Err(not_listening()),
+    }
+}
+
+/// Uses the first socket from systemd if there is one, and binds `addr` otherwise
+#[cfg(unix)]
+pub(crate) fn from_systemd_or_bind<A: std::net::ToSocketAddrs>(addr: A) -> io::Result<Listener> {
+    match from_systemd()? {
+        Some(listeners) => {
+            #[cfg(feature = "log")]
+            if listeners.len() > 1 {
+                log::warn!(
+                    "systemd passed {} sockets, only the first is used",
+                    listeners.len()
+                );
+            }
+
+            listeners
+                .into_iter()
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "systemd passed no sockets"))
+        }
+        None => from_std_tcp(std::net::TcpListener::bind(addr)?),
+    }
+}
+
+#[cfg(not(feature = "async"))]
+fn from_std_tcp(listener: std::net::TcpListener) -> io::Result<Listener> {
+    Ok(Listener::Tcp(listener))
+}
+
+#[cfg(feature = "async")]
+fn from_std_tcp(listener: std::net::TcpListener) -> io::Result<Listener> {
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener).map(Listener::Tcp)
+}
+
+#[cfg(all(unix, not(feature = "async")))]
+fn from_std_unix(listener: std::os::unix::net::UnixListener) -> io::Result<Listener> {
+    Ok(Listener::Unix(listener))
+}
+
+#[cfg(all(unix, feature = "async"))]
+fn from_std_unix(listener: std::os::unix::net::UnixListener) -> io::Result<Listener> {
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener).map(Listener::Unix)
+}
+
+/// Binds a Unix socket at `path`, replacing the socket file left behind by a server that is
+/// no longer running
+///
+/// Fails with `AddrInUse` if a server is still accepting on `path`, and never removes
+/// anything that isn't a socket.
+///
+/// ```ignore
+/// let socket = bind_unix("/run/tinyhttp.sock")?;
+/// HttpListener::new(socket, config).start();
+/// ```
+#[cfg(unix)]
+pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+    remove_stale_socket(path)?;
+    UnixListener::bind(path)
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+
+    // Only a socket nobody is listening on is safe to remove
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("a server is already listening on {}", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            #[cfg(feature = "log")]
+            log::info!("removing stale socket {}", path.display());
+
+            std::fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{headers::HeaderMap, request::RequestError};
+
+/// Empty lines ignored before a request line, like a CRLF a client sent after the last body
+/// (RFC 9112 2.2)
+pub(crate) const MAX_EMPTY_LINES: usize = 8;
+
+/// How the body of a request is delimited
+pub(crate) enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
+
+/// Reads a single CRLF terminated line of at most `limit` bytes (excluding the CRLF)
+///
+/// Returns `too_long` if the line doesn't end within the limit, and `malformed` if
+/// the stream ends first or the line isn't terminated by a CRLF.
+#[cfg(not(feature = "async"))]
+pub(crate) fn read_line_limited<R: std::io::BufRead>(
+    reader: &mut R,
+    limit: usize,
+    too_long: RequestError,
+    malformed: RequestError,
+) -> Result<Vec<u8>, RequestError> {
+    use std::io::{BufRead, Read};
+
+    let mut line = Vec::new();
+    if let Err(e) = reader
+        .by_ref()
+        .take((limit as u64).saturating_add(2))
+        .read_until(b'\n', &mut line)
+    {
+        return Err(read_err(e, malformed));
+    }
+
+    finish_line(line, limit, too_long, malformed)
+}
+
+/// Maps a failed read to `Timeout` if the connection stalled, and to `err` otherwise
+pub(crate) fn read_err(e: std::io::Error, err: RequestError) -> RequestError {
+    match e.kind() {
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => RequestError::Timeout,
+        _ => err,
+    }
+}
+
+/// Checks a line read by `read_until(b'\n')` with `limit + 2` bytes allowed, and strips the CRLF
+pub(crate) fn finish_line(
+    mut line: Vec<u8>,
+    limit: usize,
+    too_long: RequestError,
+    malformed: RequestError,
+) -> Result<Vec<u8>, RequestError> {
+    if !line.ends_with(b"\n") {
+        return Err(if line.len() > limit {
+            too_long
+        } else {
+            malformed
+        });
+    }
+
+    if !line.ends_with(b"\r\n") {
+        return Err(malformed);
+    }
+
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+/// Splits the request line into its method, target and version
+pub(crate) fn parse_status_line(line: &[u8]) -> Result<Vec<String>, RequestError> {
+    let line = std::str::from_utf8(line).map_err(|_| RequestError::StatusLineErr)?;
+
+    #[cfg(feature = "log")]
+    log::trace!("STATUS LINE: {:#?}", line);
+
+    let status_line: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
+    if status_line.len() != 3 {
+        return Err(RequestError::StatusLineErr);
+    }
+
+    match status_line[2].as_str() {
+        "HTTP/1.0" | "HTTP/1.1" => Ok(status_line),
+        v if v.starts_with("HTTP/") => Err(RequestError::UnsupportedVersion),
+        _ => Err(RequestError::StatusLineErr),
+    }
+}
+
+/// Splits a header line into its name and value
+pub(crate) fn parse_header_line(line: &[u8]) -> Result<(&str, &str), RequestError> {
+    let header = std::str::from_utf8(line).map_err(|_| RequestError::HeaderNotUtf8)?;
+
+    #[cfg(feature = "log")]
+    log::trace!("HEADER: {:?}", header);
+
+    let (key, val) = header
+        .split_once(':')
+        .ok_or(RequestError::HeaderMissingColon)?;
+
+    // No whitespace is allowed between the name and the colon (RFC 9112 5.1), since
+    // `Content-Length : 5` would otherwise be stored under a name nothing looks up
+    if key.is_empty() || !key.bytes().all(is_token_char) {
+        return Err(RequestError::InvalidHeaderName);
+    }
+
+    Ok((key, val.trim()))
+}
+
+/// Whether `byte` may appear in a header field name (`tchar` in RFC 9110 5.6.2)
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// Adds a header field read from the request to `headers`
+///
+/// Repeated `Content-Length` fields are refused unless they all have the same value, since
+/// keeping only one of them would frame the body differently from a proxy keeping another.
+pub(crate) fn add_header(
+    headers: &mut HeaderMap,
+    key: &str,
+    val: &str,
+) -> Result<(), RequestError> {
+    if key.eq_ignore_ascii_case("Content-Length") {
+        if let Some(existing) = headers.get(key) {
+            if existing != val {
+                return Err(RequestError::BadContentLength);
+            }
+        }
+    }
+
+    // Repeated field lines make up one comma separated list (RFC 9110 5.3), so a
+    // second Transfer-Encoding can't replace the first one unnoticed
+    if key.eq_ignore_ascii_case("Transfer-Encoding") {
+        if let Some(existing) = headers.get(key) {
+            let joined = format!("{existing}, {val}");
+            headers.set(key, joined);
+            return Ok(());
+        }
+    }
+
+    headers.set(key, val);
+    Ok(())
+}
+
+/// Works out how the body is framed, refusing bodies larger than `max_body_bytes`
+pub(crate) fn body_length(
+    headers: &HeaderMap,
+    max_body_bytes: usize,
+) -> Result<BodyLength, RequestError> {
+    if let Some(encoding) = headers.get("Transfer-Encoding") {
+        // A message with both headers could be framed differently by a proxy
+        // in front of us, so refuse it rather than guess (RFC 9112 6.1)
+        if headers.contains("Content-Length") {
+            return Err(RequestError::AmbiguousBodyLength);
+        }
+
+        // Only plain chunked is supported, any other coding in the list would
+        // have to be undone before the body could be handed to a route
+        if !encoding.trim().eq_ignore_ascii_case("chunked") {
+            return Err(RequestError::TransferEncodingErr);
+        }
+
+        return Ok(BodyLength::Chunked);
+    }
+
+    let body_len = match headers.get("Content-Length") {
+        // `usize::from_str` also takes a leading `+`, which other parsers may not
+        Some(len) if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) => {
+            return Err(RequestError::BadContentLength)
+        }
+        Some(len) => len
+            .parse::<usize>()
+            .map_err(|_| RequestError::BadContentLength)?,
+        None => 0,
+    };
+
+    if body_len > max_body_bytes {
+        return Err(RequestError::PayloadTooLarge);
+    }
+
+    Ok(BodyLength::Fixed(body_len))
+}
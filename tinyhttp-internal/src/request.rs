@@ -1,4 +1,4 @@
-use std::{fmt::Display, mem};
+use std::{fmt::Display, mem, ops::Deref, str::FromStr};
 
 #[derive(Clone, Debug, Default)]
 pub struct Wildcard<T: Display> {
@@ -17,6 +17,59 @@ impl<T: Display> Wildcard<T> {
     }
 }
 
+/// Query string parameters, decoded and in the order they were sent
+///
+/// Keys may repeat, `get` returns the first value and `get_all` every value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Query {
+    params: Vec<(String, String)>,
+}
+
+impl Query {
+    /// Parses a query string such as `a=1&b=two+words&b=%2F`
+    pub fn parse(query: &str) -> Query {
+        let params = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(key, true), percent_decode(value, true))
+            })
+            .collect();
+
+        Query { params }
+    }
+
+    /// The first value of `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of `key`, in order
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.params
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.params.iter().any(|(k, _)| k == key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
 /// Struct containing data on a single request.
 ///
 /// parsed_body which is a Option<String> that can contain the body as a String
@@ -28,6 +81,7 @@ pub struct Request {
     status_line: Vec<String>,
     body: Vec<u8>,
     wildcard: Option<String>,
+    params: Vec<(String, String)>,
     is_http2: bool,
 }
 
@@ -49,15 +103,33 @@ impl Request {
             raw_headers,
             status_line,
             wildcard,
+            params: Vec::new(),
             is_http2: false,
         }
     }
 
-    pub(crate) fn set_wildcard(&mut self, w: Option<String>) -> &Self {
-        self.wildcard = w;
+    /// Stores the path parameters of the matched route
+    ///
+    /// The last one doubles as the wildcard, which is what single parameter routes used to get.
+    pub(crate) fn set_params(&mut self, params: Vec<(String, String)>) -> &Self {
+        self.wildcard = params.last().map(|(_, value)| value.clone());
+        self.params = params;
         self
     }
 
+    /// A copy of the request without its body
+    #[cfg(not(feature = "async"))]
+    pub(crate) fn without_body(&self) -> Request {
+        Request {
+            raw_headers: self.raw_headers.clone(),
+            status_line: self.status_line.clone(),
+            body: Vec::new(),
+            wildcard: self.wildcard.clone(),
+            params: self.params.clone(),
+            is_http2: self.is_http2,
+        }
+    }
+
     /// Get request body as bytes
     pub fn get_raw_body(&self) -> &[u8] {
         &self.body
@@ -81,6 +153,52 @@ impl Request {
         &self.status_line
     }
 
+    /// Get the path of the request target, without the query string
+    pub fn get_path(&self) -> &str {
+        split_target(self.target()).0
+    }
+
+    /// Get the raw query string, without the leading `?`
+    pub fn get_query_string(&self) -> Option<&str> {
+        split_target(self.target()).1
+    }
+
+    /// Get the decoded query parameters
+    pub fn get_query(&self) -> Query {
+        Query::parse(self.get_query_string().unwrap_or_default())
+    }
+
+    /// Get the first decoded value of a query parameter
+    pub fn get_query_param(&self, key: &str) -> Option<String> {
+        self.get_query().get(key).map(str::to_owned)
+    }
+
+    /// Get every decoded value of a repeated query parameter
+    pub fn get_query_params(&self, key: &str) -> Vec<String> {
+        self.get_query()
+            .get_all(key)
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn target(&self) -> &str {
+        self.status_line.get(1).map_or("", String::as_str)
+    }
+
+    /// Get a path parameter by the name it has in the route, e.g. `id` in `/users/:id`
+    pub fn get_param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get every path parameter, in the order they appear in the route
+    pub fn get_params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
     pub fn get_wildcard(&self) -> Option<&String> {
         self.wildcard.as_ref()
     }
@@ -96,6 +214,122 @@ impl Request {
     }
 }
 
+/// Builds a handler argument from the request
+///
+/// The `#[get]`/`#[post]` macros extract their argument through this trait. When it fails,
+/// the returned response is sent instead of calling the handler. Every `From<&mut Request>`
+/// type is an extractor that can't fail.
+#[allow(clippy::result_large_err)]
+pub trait FromRequest<'a>: Sized {
+    fn from_request(req: &'a mut Request) -> Result<Self, Response>;
+}
+
+impl<'a, T: From<&'a mut Request>> FromRequest<'a> for T {
+    fn from_request(req: &'a mut Request) -> Result<Self, Response> {
+        Ok(req.into())
+    }
+}
+
+/// Path parameters parsed into `T`
+///
+/// `T` is a single value for routes with one parameter, a tuple with the parameters in the
+/// order they appear in the route, or a struct deriving `FromParams` to take them by name.
+/// A parameter that doesn't parse is answered with a 400.
+///
+/// ```ignore
+/// #[get("/users/:id/posts/:post_id")]
+/// fn post(Path((id, post_id)): Path<(u32, u32)>) -> String {
+///     format!("post {post_id} by {id}")
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Path<T>(pub T);
+
+impl<T> Path<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Path<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<'a, T: FromParams> FromRequest<'a> for Path<T> {
+    fn from_request(req: &'a mut Request) -> Result<Self, Response> {
+        T::from_params(req.get_params())
+            .map(Path)
+            .map_err(Response::from)
+    }
+}
+
+/// Types that can be built from the path parameters of a route
+pub trait FromParams: Sized {
+    fn from_params(params: &[(String, String)]) -> Result<Self, ParamError>;
+}
+
+/// Parses the path parameter called `name`, used by `#[derive(FromParams)]`
+pub fn parse_param<T: FromStr>(params: &[(String, String)], name: &str) -> Result<T, ParamError> {
+    let (name, value) = params
+        .iter()
+        .find(|(k, _)| k == name)
+        .ok_or_else(|| ParamError::Missing(name.to_string()))?;
+    parse_value(name, value)
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, ParamError> {
+    value.parse().map_err(|_| ParamError::Invalid {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+/// Takes exactly `N` parameters, for the positional impls
+fn positional<const N: usize>(
+    params: &[(String, String)],
+) -> Result<&[(String, String); N], ParamError> {
+    params
+        .try_into()
+        .map_err(|_| ParamError::Count(N, params.len()))
+}
+
+macro_rules! impl_from_params_for_values {
+    ($($ty:ty),*) => {
+        $(
+            impl FromParams for $ty {
+                fn from_params(params: &[(String, String)]) -> Result<Self, ParamError> {
+                    let [(name, value)] = positional(params)?;
+                    parse_value(name, value)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_params_for_values!(
+    String, bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
+
+macro_rules! impl_from_params_for_tuples {
+    ($(($($ty:ident),+)),*) => {
+        $(
+            impl<$($ty: FromStr),+> FromParams for ($($ty,)+) {
+                #[allow(non_snake_case)]
+                fn from_params(params: &[(String, String)]) -> Result<Self, ParamError> {
+                    let [$($ty),+] = positional(params)?;
+                    Ok(($(parse_value(&$ty.0, &$ty.1)?,)+))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_params_for_tuples!((A), (A, B), (A, B, C), (A, B, C, D));
+
 impl<'a> From<&'a mut Request> for Wildcard<&'a str> {
     fn from(value: &'a mut Request) -> Self {
         Wildcard {
@@ -131,6 +365,12 @@ impl<'a> From<&'a mut Request> for Option<&'a str> {
     }
 }
 
+impl From<&mut Request> for Query {
+    fn from(value: &mut Request) -> Self {
+        value.get_query()
+    }
+}
+
 impl From<&mut Request> for Request {
     fn from(value: &mut Request) -> Self {
         mem::take(value)
@@ -138,12 +378,58 @@ impl From<&mut Request> for Request {
 }
 use thiserror::Error;
 
-use crate::headers::HeaderMap;
+use crate::{
+    headers::HeaderMap,
+    response::Response,
+    url::{percent_decode, split_target},
+};
 
 #[derive(Error, Debug)]
 pub enum RequestError {
     #[error("failed to parse status line")]
     StatusLineErr,
+    #[error("request line is too long")]
+    RequestLineTooLong,
     #[error("failed to parse headers")]
     HeadersErr,
+    #[error("header is missing a colon")]
+    HeaderMissingColon,
+    #[error("invalid header name")]
+    InvalidHeaderName,
+    #[error("header is not valid UTF-8")]
+    HeaderNotUtf8,
+    #[error("request headers are too large")]
+    HeadersTooLarge,
+    #[error("request has too many headers")]
+    TooManyHeaders,
+    #[error("invalid Content-Length header")]
+    BadContentLength,
+    #[error("request body is shorter than its declared length")]
+    TruncatedBody,
+    #[error("request body is too large")]
+    PayloadTooLarge,
+    #[error("timed out reading request")]
+    Timeout,
+    #[error("unsupported HTTP version")]
+    UnsupportedVersion,
+    #[error("request has both Content-Length and Transfer-Encoding headers")]
+    AmbiguousBodyLength,
+    #[error("unsupported transfer encoding")]
+    TransferEncodingErr,
+    #[error("failed to decode chunked body")]
+    ChunkedBodyErr,
+}
+
+/// Why path parameters couldn't be extracted
+///
+/// A value that doesn't parse is the client's fault, the others mean the extractor doesn't
+/// fit the route.
+#[derive(Error, Debug)]
+pub enum ParamError {
+    #[error("invalid value {value:?} for path parameter `{name}`")]
+    Invalid { name: String, value: String },
+    #[error("route has no path parameter `{0}`")]
+    Missing(String),
+    #[error("expected {0} path parameters, route has {1}")]
+    Count(usize, usize),
 }
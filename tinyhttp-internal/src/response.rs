@@ -1,17 +1,121 @@
 use std::{
     collections::HashMap,
     error::Error,
-    io::{Read, Write},
+    fmt::Debug,
+    io::Read,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use crate::request::{ParamError, RequestError};
+
+#[cfg(not(feature = "async"))]
+use std::io::Write;
+
 #[cfg(feature = "async")]
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+/// A response body that is read incrementally instead of being held in memory
+///
+/// Clones share the same underlying reader.
+#[derive(Clone)]
+pub struct BodyStream(Arc<Mutex<Box<dyn Read + Send>>>);
+
+impl BodyStream {
+    pub fn new<R: Read + Send + 'static>(reader: R) -> BodyStream {
+        BodyStream(Arc::new(Mutex::new(Box::new(reader))))
+    }
+}
+
+impl Read for BodyStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Debug for BodyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+/// An HTTP status code
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StatusCode(pub u16);
+
+impl StatusCode {
+    pub const OK: StatusCode = StatusCode(200);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+
+    pub fn as_u16(self) -> u16 {
+        self.0
+    }
+
+    /// Whether this is a 4xx or 5xx status
+    pub fn is_error(self) -> bool {
+        self.0 >= 400
+    }
+
+    /// Whether a response with this status can have a body, which 1xx, 204 and 304 can't
+    pub fn allows_body(self) -> bool {
+        !matches!(self.0, 100..=199 | 204 | 304)
+    }
+
+    /// The standard reason phrase, or an empty string for codes it doesn't know
+    pub fn reason(self) -> &'static str {
+        match self.0 {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            414 => "URI Too Long",
+            422 => "Unprocessable Content",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+
+    /// The HTTP/1.1 status line for this code
+    pub fn status_line(self) -> String {
+        format!("HTTP/1.1 {self}\r\n")
+    }
+}
+
+impl std::fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason() {
+            "" => write!(f, "{}", self.0),
+            reason => write!(f, "{} {}", self.0, reason),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Response {
     pub headers: HashMap<String, String>,
     pub status_line: String,
     pub body: Option<Vec<u8>>,
+    pub stream: Option<BodyStream>,
     pub mime: Option<String>,
     pub http2: bool,
     pub(crate) manual_override: bool,
@@ -77,13 +181,49 @@ impl From<Box<dyn Error>> for Response {
     }
 }
 
+impl From<RequestError> for Response {
+    fn from(value: RequestError) -> Self {
+        let status_line = match value {
+            RequestError::RequestLineTooLong => "HTTP/1.1 414 URI Too Long",
+            RequestError::HeadersTooLarge | RequestError::TooManyHeaders => {
+                "HTTP/1.1 431 Request Header Fields Too Large"
+            }
+            RequestError::PayloadTooLarge => "HTTP/1.1 413 Payload Too Large",
+            RequestError::Timeout => "HTTP/1.1 408 Request Timeout",
+            RequestError::UnsupportedVersion => "HTTP/1.1 505 HTTP Version Not Supported",
+            RequestError::TransferEncodingErr => "HTTP/1.1 501 Not Implemented",
+            _ => "HTTP/1.1 400 Bad Request",
+        };
+
+        Response::new()
+            .body(value.to_string().into_bytes())
+            .mime("text/plain")
+            .status_line(status_line)
+    }
+}
+
+impl From<ParamError> for Response {
+    fn from(value: ParamError) -> Self {
+        let status_line = match value {
+            ParamError::Invalid { .. } => "HTTP/1.1 400 Bad Request",
+            _ => "HTTP/1.1 500 Internal Server Error",
+        };
+
+        Response::new()
+            .body(value.to_string().into_bytes())
+            .mime("text/plain")
+            .status_line(status_line)
+    }
+}
+
 impl Response {
     pub fn new() -> Response {
         Response {
             headers: HashMap::new(),
             mime: None,
             body: None,
-            status_line: String::from("HTTP/1.1 200 OK"),
+            stream: None,
+            status_line: String::from("HTTP/1.1 200 OK\r\n"),
             http2: false,
             manual_override: false,
         }
@@ -94,6 +234,7 @@ impl Response {
             headers: HashMap::new(),
             status_line: String::new(),
             body: None,
+            stream: None,
             mime: None,
             manual_override: true,
             http2: false,
@@ -111,11 +252,38 @@ impl Response {
         self
     }
 
+    /// The status code of the status line, if it has a valid one
+    pub fn get_status(&self) -> Option<StatusCode> {
+        let code = self.status_line.split_whitespace().nth(1)?;
+        code.parse().ok().map(StatusCode)
+    }
+
+    /// The body as text, or an empty string if there is none
+    ///
+    /// Invalid UTF-8 is replaced, so this is meant for tests rather than for reading bodies.
+    pub fn body_string(&self) -> String {
+        String::from_utf8_lossy(self.body.as_deref().unwrap_or_default()).into_owned()
+    }
+
     pub fn body(mut self, body: Vec<u8>) -> Self {
         self.body = Some(body);
         self
     }
 
+    /// Stream the body from a reader instead of buffering it
+    ///
+    /// The server sends it with `Transfer-Encoding: chunked`, since the length isn't known up front.
+    ///
+    /// ```ignore
+    /// let file = std::fs::File::open("report.csv")?;
+    /// Response::new().mime("text/csv").stream(file)
+    /// ```
+    pub fn stream<R: Read + Send + 'static>(mut self, reader: R) -> Self {
+        self.body = None;
+        self.stream = Some(BodyStream::new(reader));
+        self
+    }
+
     pub fn mime<P>(mut self, mime: P) -> Self
     where
         P: Into<String>,
@@ -126,76 +294,293 @@ impl Response {
 
     #[cfg(not(feature = "async"))]
     pub fn send<P: Read + Write>(self, sock: &mut P) {
-        let line_bytes = self.status_line.as_bytes();
+        if let Err(_e) = self.write_to(sock) {
+            #[cfg(feature = "log")]
+            log::error!("failed to send response: {}", _e);
+        }
+    }
+
+    /// Writes the response to `sock`, stopping at the first failed write
+    #[cfg(not(feature = "async"))]
+    pub(crate) fn write_to<P: Write + ?Sized>(self, sock: &mut P) -> std::io::Result<()> {
+        let is_chunked = self.is_chunked();
+        let head = self.head_bytes();
+
         #[cfg(feature = "log")]
-        log::trace!("res status line: {:#?}", self.status_line);
+        log::trace!("size of response head: {}", head.len());
 
-        let mut header_bytes: Vec<u8> = self
-            .headers
-            .into_iter()
-            .flat_map(|(i, j)| [(i + ": ").as_bytes(), (j + "\r\n").as_bytes()].concat())
-            .collect();
+        sock.write_all(&[head.as_slice(), self.body.as_deref().unwrap_or_default()].concat())?;
 
-        header_bytes.extend(b"\r\n");
+        if let Some(mut stream) = self.stream {
+            write_stream(&mut stream, sock, is_chunked)?;
+        }
 
-        #[cfg(all(feature = "log", debug_assertions))]
-        {
-            log::trace!(
-                "HEADER AS STR: {}",
-                String::from_utf8(header_bytes.clone()).unwrap()
-            );
-            log::trace!(
-                "STATUS LINE AS STR: {}",
-                std::str::from_utf8(line_bytes).unwrap()
-            );
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn send<P: AsyncReadExt + AsyncWriteExt + Unpin>(&self, sock: &mut P) {
+        if let Err(_e) = self.write_to(sock).await {
+            #[cfg(feature = "log")]
+            log::error!("failed to send response: {}", _e);
+        }
+    }
+
+    /// Async version of `write_to`
+    #[cfg(feature = "async")]
+    pub(crate) async fn write_to<P: AsyncWriteExt + Unpin>(
+        &self,
+        sock: &mut P,
+    ) -> std::io::Result<()> {
+        let head = self.head_bytes();
+        sock.write_all(&[head.as_slice(), self.body.as_deref().unwrap_or_default()].concat())
+            .await?;
+
+        if let Some(stream) = &self.stream {
+            write_stream(&mut stream.clone(), sock, self.is_chunked()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets `Content-Type` from the mime type, or sniffs it from the body if none was given
+    pub(crate) fn set_content_type(&mut self) {
+        let mime = match self.mime {
+            Some(ref mime) => mime.to_owned(),
+            None if self.has_header("Content-Type") => return,
+            None => match &self.body {
+                Some(body) => infer::get(body)
+                    .map(|mime| mime.mime_type())
+                    .unwrap_or("text/plain")
+                    .to_string(),
+                None if self.stream.is_some() => "application/octet-stream".to_string(),
+                None => return,
+            },
         };
 
-        let full_req: &[u8] = &[
-            line_bytes,
-            header_bytes.as_slice(),
-            self.body.as_ref().unwrap_or(&vec![]),
-        ]
-        .concat();
+        self.headers.insert("Content-Type".to_string(), mime);
+    }
 
-        #[cfg(feature = "log")]
-        log::trace!("size of response: {}", full_req.len());
+    /// Sets the headers that frame the body and the connection
+    ///
+    /// Buffered bodies get a `Content-Length`. Streams are sent chunked if the client
+    /// supports it, or delimited by closing the connection otherwise. HEAD responses
+    /// keep the headers of the full response but lose the body, and 1xx, 204 and 304
+    /// responses are sent without either (RFC 9110 8.6).
+    ///
+    /// Returns whether the connection can stay open after this response.
+    pub(crate) fn set_framing(
+        &mut self,
+        mut keep_alive: bool,
+        supports_chunked: bool,
+        is_head: bool,
+    ) -> bool {
+        // A handler asking to close the connection still gets its way
+        if self.headers.iter().any(|(k, v)| {
+            k.eq_ignore_ascii_case("Connection") && v.trim().eq_ignore_ascii_case("close")
+        }) {
+            keep_alive = false;
+        }
+
+        // Framing is decided here, so whatever a handler set (in any case) can't end
+        // up next to the headers below, like a Content-Length alongside chunked
+        self.headers.retain(|k, _| {
+            !k.eq_ignore_ascii_case("Content-Length")
+                && !k.eq_ignore_ascii_case("Transfer-Encoding")
+                && !k.eq_ignore_ascii_case("Connection")
+        });
 
-        sock.write_all(full_req).unwrap();
+        if !self.allows_body() {
+            self.body = None;
+            self.stream = None;
+        } else if self.stream.is_some() {
+            if supports_chunked {
+                self.headers
+                    .insert("Transfer-Encoding".to_string(), "chunked".to_string());
+            } else {
+                keep_alive = false;
+            }
+        } else {
+            let body_len = self.body.as_ref().map_or(0, |b| b.len());
+            self.headers
+                .insert("Content-Length".to_string(), body_len.to_string());
+        }
+
+        self.headers.insert(
+            "Connection".to_string(),
+            if keep_alive { "keep-alive" } else { "close" }.to_string(),
+        );
+
+        if is_head {
+            self.body = None;
+            self.stream = None;
+        }
+
+        keep_alive
     }
 
-    #[cfg(feature = "async")]
-    pub(crate) async fn send<P: AsyncReadExt + AsyncWriteExt + Unpin>(&self, sock: &mut P) {
-        let line_bytes = self.status_line.as_bytes();
+    pub(crate) fn allows_body(&self) -> bool {
+        self.get_status().is_none_or(StatusCode::allows_body)
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers.keys().any(|k| k.eq_ignore_ascii_case(name))
+    }
+
+    fn is_chunked(&self) -> bool {
+        self.headers.iter().any(|(k, v)| {
+            k.eq_ignore_ascii_case("Transfer-Encoding") && v.eq_ignore_ascii_case("chunked")
+        })
+    }
+
+    /// Serializes the status line and headers
+    ///
+    /// Buffered bodies get a `Content-Length` unless their status can't have a body, and
+    /// every response gets a `Date`, even if whoever built the response didn't set them.
+    fn head_bytes(&self) -> Vec<u8> {
         #[cfg(feature = "log")]
         log::trace!("res status line: {:#?}", self.status_line);
 
-        let mut header_bytes: Vec<u8> = self
-            .headers
-            .iter()
-            .flat_map(|s| [s.0.as_bytes(), s.1.as_bytes()].concat())
-            .collect();
+        let mut head = self.status_line.clone().into_bytes();
+        let mut push_header = |key: &str, val: &str| {
+            head.extend([key.as_bytes(), b": ", val.as_bytes(), b"\r\n"].concat());
+        };
+
+        for (key, val) in &self.headers {
+            push_header(key, val);
+        }
+
+        if self.stream.is_none()
+            && self.allows_body()
+            && !self.has_header("Content-Length")
+            && !self.has_header("Transfer-Encoding")
+        {
+            let body_len = self.body.as_ref().map_or(0, |b| b.len());
+            push_header("Content-Length", &body_len.to_string());
+        }
 
-        header_bytes.extend(b"\r\n");
+        if !self.has_header("Date") {
+            push_header("Date", &http_date(SystemTime::now()));
+        }
+
+        head.extend(b"\r\n");
 
         #[cfg(all(feature = "log", debug_assertions))]
-        {
-            log::trace!(
-                "HEADER AS STR: {}",
-                String::from_utf8(header_bytes.clone()).unwrap()
-            );
-            log::trace!(
-                "STATUS LINE AS STR: {}",
-                std::str::from_utf8(line_bytes).unwrap()
-            );
+        log::trace!("HEADER AS STR: {}", String::from_utf8_lossy(&head));
+
+        head
+    }
+}
+
+/// Formats `time` as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+
+    // Converts days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Copies `stream` to `sock`, framing every read as a chunk when `chunked` is set
+///
+/// If reading the stream fails, the body is left unterminated so the client can tell it was cut short.
+#[cfg(not(feature = "async"))]
+fn write_stream<R: Read, W: Write + ?Sized>(
+    stream: &mut R,
+    sock: &mut W,
+    chunked: bool,
+) -> std::io::Result<()> {
+    let mut buf = vec![0; 8192];
+    loop {
+        let len = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                #[cfg(feature = "log")]
+                log::error!("failed to read response stream: {}", e);
+
+                return Err(e);
+            }
         };
 
-        let full_req: &[u8] = &[
-            line_bytes,
-            header_bytes.as_slice(),
-            self.body.as_ref().unwrap(),
-        ]
-        .concat();
+        if chunked {
+            sock.write_all(format!("{:X}\r\n", len).as_bytes())?;
+            sock.write_all(&buf[..len])?;
+            sock.write_all(b"\r\n")?;
+        } else {
+            sock.write_all(&buf[..len])?;
+        }
+    }
 
-        sock.write_all(full_req).await.unwrap();
+    if chunked {
+        sock.write_all(b"0\r\n\r\n")?;
     }
+
+    Ok(())
+}
+
+/// Async version of `write_stream`
+///
+/// The stream itself is a blocking reader, so it is best backed by something that reads quickly.
+#[cfg(feature = "async")]
+async fn write_stream<R: Read, W: AsyncWriteExt + Unpin>(
+    stream: &mut R,
+    sock: &mut W,
+    chunked: bool,
+) -> std::io::Result<()> {
+    let mut buf = vec![0; 8192];
+    loop {
+        let len = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                #[cfg(feature = "log")]
+                log::error!("failed to read response stream: {}", e);
+
+                return Err(e);
+            }
+        };
+
+        if chunked {
+            sock.write_all(format!("{:X}\r\n", len).as_bytes()).await?;
+            sock.write_all(&buf[..len]).await?;
+            sock.write_all(b"\r\n").await?;
+        } else {
+            sock.write_all(&buf[..len]).await?;
+        }
+    }
+
+    if chunked {
+        sock.write_all(b"0\r\n\r\n").await?;
+    }
+
+    Ok(())
 }
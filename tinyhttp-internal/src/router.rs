@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    config::{Method, Route},
+    url::percent_decode,
+};
+
+/// The route found for a request, with the path parameters it captured
+pub(crate) struct RouteMatch<'a> {
+    pub route: &'a dyn Route,
+    pub params: Vec<(String, String)>,
+}
+
+/// Looks up routes by request path, one path segment at a time
+///
+/// A pattern segment is either static, a `:name` parameter matching exactly one segment,
+/// or a `*name` catch-all matching the rest of the path. At every level static children
+/// are tried first, then the parameter, then the catch-all, so the route that wins never
+/// depends on the order routes were added in.
+#[derive(Clone, Default)]
+pub(crate) struct Router {
+    root: Node,
+}
+
+#[derive(Clone, Default)]
+struct Node {
+    statics: BTreeMap<String, Node>,
+    param: Option<Box<Node>>,
+    catch_all: Option<Box<Node>>,
+    endpoints: HashMap<Method, Endpoint>,
+}
+
+#[derive(Clone)]
+struct Endpoint {
+    route: Box<dyn Route>,
+    param_names: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Segment<'a> {
+    Static(&'a str),
+    Param(&'a str),
+    CatchAll(&'a str),
+}
+
+/// Splits a route pattern into segments
+///
+/// A bare `:` as the last segment is the old wildcard syntax, and matches the rest of the path.
+pub(crate) fn parse_pattern(pattern: &str) -> Vec<Segment<'_>> {
+    let segments: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    let last = segments.len().saturating_sub(1);
+
+    segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| {
+            if let Some(name) = segment.strip_prefix('*') {
+                Segment::CatchAll(name)
+            } else if *segment == ":" && i == last {
+                Segment::CatchAll("")
+            } else if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name)
+            } else {
+                Segment::Static(segment)
+            }
+        })
+        .collect()
+}
+
+/// Checks that a pattern can be matched as written
+///
+/// A catch-all has to be the last segment, and every parameter needs a name that no other
+/// parameter in the pattern uses. The trailing bare `:` wildcard is still allowed.
+pub(crate) fn check_pattern(segments: &[Segment<'_>]) -> Result<(), String> {
+    let mut names = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        let name = match *segment {
+            Segment::Static(_) => continue,
+            Segment::Param("") => return Err("parameters need a name, like `:id`".into()),
+            Segment::Param(name) => name,
+            Segment::CatchAll(name) if i + 1 < segments.len() => {
+                return Err(format!(
+                    "catch-all `*{name}` must be the last segment of the path"
+                ))
+            }
+            Segment::CatchAll(name) => name,
+        };
+
+        if !name.is_empty() && names.contains(&name) {
+            return Err(format!("parameter `{name}` is used more than once"));
+        }
+        names.push(name);
+    }
+
+    Ok(())
+}
+
+/// The pattern `route` is matched with
+pub(crate) fn route_pattern(route: &dyn Route) -> String {
+    let path = route.get_path();
+    // Routes built by hand may still name their wildcard separately from the path
+    match route.wildcard() {
+        Some(wildcard) if !path.contains([':', '*']) => {
+            format!("{}/*{}", path.trim_end_matches('/'), wildcard)
+        }
+        _ => path.to_string(),
+    }
+}
+
+impl Router {
+    /// Adds `route`, returning the route it replaced if one had the same method and pattern
+    ///
+    /// Patterns only differing in their parameter names, like `/users/:id` and
+    /// `/users/:user_id`, are the same pattern.
+    ///
+    /// # Panics
+    ///
+    /// If the pattern is invalid, see [`check_pattern`].
+    pub(crate) fn insert(&mut self, route: Box<dyn Route>) -> Option<Box<dyn Route>> {
+        let pattern = route_pattern(route.as_ref());
+        let segments = parse_pattern(&pattern);
+        if let Err(e) = check_pattern(&segments) {
+            panic!("invalid route pattern `{pattern}`: {e}");
+        }
+
+        let mut node = &mut self.root;
+        let mut param_names = Vec::new();
+        for segment in segments {
+            match segment {
+                Segment::Static(segment) => {
+                    node = node.statics.entry(segment.to_string()).or_default();
+                }
+                Segment::Param(name) => {
+                    param_names.push(name.to_string());
+                    node = node.param.get_or_insert_with(Default::default);
+                }
+                Segment::CatchAll(name) => {
+                    param_names.push(name.to_string());
+                    node = node.catch_all.get_or_insert_with(Default::default);
+                    break;
+                }
+            }
+        }
+
+        node.endpoints
+            .insert(route.get_method(), Endpoint { route, param_names })
+            .map(|replaced| replaced.route)
+    }
+
+    /// Every route, in no particular order
+    pub(crate) fn routes(&self) -> Vec<&dyn Route> {
+        let mut routes = Vec::new();
+        self.root.collect(&mut routes);
+        routes
+    }
+
+    /// Finds the route for `method` matching `path`, which must not contain a query string
+    pub(crate) fn find(&self, method: Method, path: &str) -> Option<RouteMatch<'_>> {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let mut values = Vec::new();
+        let endpoint = self.root.find(method, &segments, &mut values)?;
+
+        let params = endpoint
+            .param_names
+            .iter()
+            .cloned()
+            .zip(values.iter().map(|value| percent_decode(value, false)))
+            .collect();
+
+        Some(RouteMatch {
+            route: endpoint.route.as_ref(),
+            params,
+        })
+    }
+}
+
+impl Node {
+    fn collect<'a>(&'a self, routes: &mut Vec<&'a dyn Route>) {
+        routes.extend(self.endpoints.values().map(|e| e.route.as_ref()));
+
+        let children = self.statics.values();
+        for node in children
+            .chain(self.param.as_deref())
+            .chain(self.catch_all.as_deref())
+        {
+            node.collect(routes);
+        }
+    }
+
+    /// Walks the remaining `segments`, pushing a value for every parameter on the way
+    ///
+    /// `values` is left as it was when nothing matches, so siblings can be tried next.
+    fn find(
+        &self,
+        method: Method,
+        segments: &[&str],
+        values: &mut Vec<String>,
+    ) -> Option<&Endpoint> {
+        let Some((first, rest)) = segments.split_first() else {
+            if let Some(endpoint) = self.endpoints.get(&method) {
+                return Some(endpoint);
+            }
+            // A catch-all also matches when nothing is left
+            let endpoint = self.catch_all.as_ref()?.endpoints.get(&method)?;
+            values.push(String::new());
+            return Some(endpoint);
+        };
+
+        if let Some(endpoint) = self
+            .statics
+            .get(*first)
+            .and_then(|node| node.find(method, rest, values))
+        {
+            return Some(endpoint);
+        }
+
+        if let Some(node) = &self.param {
+            values.push(first.to_string());
+            if let Some(endpoint) = node.find(method, rest, values) {
+                return Some(endpoint);
+            }
+            values.pop();
+        }
+
+        let endpoint = self.catch_all.as_ref()?.endpoints.get(&method)?;
+        values.push(segments.join("/"));
+        Some(endpoint)
+    }
+}
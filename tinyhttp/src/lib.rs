@@ -88,31 +88,27 @@ pub mod prelude {
     pub use tinyhttp_codegen::*;
     pub use tinyhttp_internal::codegen::route::*;
     pub use tinyhttp_internal::config::*;
+    pub use tinyhttp_internal::connection::Connection;
+    pub use tinyhttp_internal::middleware::MiddlewareResponse;
+    pub use tinyhttp_internal::request::Query;
     pub use tinyhttp_internal::request::Request;
     pub use tinyhttp_internal::request::Wildcard;
-    pub use tinyhttp_internal::response::Response;
+    pub use tinyhttp_internal::request::{parse_param, FromParams, FromRequest, ParamError, Path};
+    pub use tinyhttp_internal::response::{Response, StatusCode};
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{sync::OnceLock, thread, time::Duration};
-
-    static HTTP_ENABLED: OnceLock<bool> = OnceLock::new();
+    use std::{thread, time::Duration};
 
     use crate::prelude::*;
 
-    fn setup_http_server() -> Result<(), Box<dyn std::error::Error>> {
-        let sock = std::net::TcpListener::bind("127.0.0.1:23195");
-        if sock.is_err() {
-            return Ok(());
-        };
-        let sock = sock?;
-
-        simple_logger::SimpleLogger::new()
+    fn test_server() -> Result<TestServer, Box<dyn std::error::Error>> {
+        // Every test starts its own server, but the logger can only be set once
+        let _ = simple_logger::SimpleLogger::new()
             .with_level(log::LevelFilter::Info)
             .env()
-            .init()
-            .unwrap();
+            .init();
 
         #[get("/ping")]
         fn ping() -> &'static str {
@@ -158,8 +154,70 @@ mod tests {
             format!("Hello, {body}")
         }
 
+        #[get("/search")]
+        fn search(query: Query) -> String {
+            let q = query.get("q").unwrap_or_default();
+            format!("{q}: {}", query.get_all("tag").join(","))
+        }
+
+        #[get("/stream")]
+        fn stream() -> Response {
+            let chunks = std::io::Cursor::new(b"streamed ".repeat(2048));
+            // A stale length from the handler mustn't be sent next to the chunked framing
+            Response::new()
+                .mime("text/plain")
+                .headers(std::collections::HashMap::from([(
+                    "Content-Length".to_string(),
+                    "5".to_string(),
+                )]))
+                .stream(chunks)
+        }
+
+        #[get("/framed")]
+        fn framed() -> Response {
+            Response::new()
+                .headers(std::collections::HashMap::from([
+                    ("content-length".to_string(), "999".to_string()),
+                    ("transfer-encoding".to_string(), "chunked".to_string()),
+                ]))
+                .body(b"ok".to_vec())
+        }
+
+        #[put("/item/:")]
+        fn put_item(req: Request) -> String {
+            let body = req.get_parsed_body().unwrap();
+            format!("put {}: {body}", req.get_wildcard().unwrap())
+        }
+
+        #[patch("/item")]
+        fn patch_item(body: Option<&str>) -> String {
+            format!("patched {}", body.unwrap())
+        }
+
+        #[delete("/item")]
+        fn delete_item() -> &'static str {
+            "deleted"
+        }
+
+        #[options("/item")]
+        fn options_item() -> Response {
+            Response::new()
+                .status_line("HTTP/1.1 204 No Content")
+                .headers(std::collections::HashMap::from([(
+                    "Allow".to_string(),
+                    "PUT, PATCH, DELETE, OPTIONS".to_string(),
+                )]))
+        }
+
         let routes = Routes::new(vec![
             ping(),
+            search(),
+            stream(),
+            framed(),
+            put_item(),
+            patch_item(),
+            delete_item(),
+            options_item(),
             check_headers(),
             check_post(),
             get_wildcard(),
@@ -167,22 +225,23 @@ mod tests {
             post_hello(),
         ]);
         let config = Config::new().routes(routes);
-        std::thread::spawn(move || {
-            HttpListener::new(sock, config).start();
-        });
-        HTTP_ENABLED.set(true).unwrap();
+        Ok(TestServer::start(config)?)
+    }
 
-        Ok(())
+    /// A config with only a `GET /ping` route, adjusted by `tweak` for the test at hand
+    fn ping_config(tweak: impl FnOnce(Config) -> Config) -> Config {
+        #[get("/ping")]
+        fn ping() -> &'static str {
+            "pong\n"
+        }
+
+        tweak(Config::new().routes(Routes::new(vec![ping()])))
     }
 
     #[test]
     fn respond_to_minimal_request() -> Result<(), Box<dyn std::error::Error>> {
-        if HTTP_ENABLED.get().is_none() {
-            setup_http_server()?;
-        }
-
-        thread::sleep(Duration::from_millis(100));
-        let request = minreq::get("http://127.0.0.1:23195/ping").send()?;
+        let server = test_server()?;
+        let request = minreq::get(server.url("/ping")).send()?;
         let parsed_resp = request.as_str()?;
         assert_eq!(parsed_resp, "pong\n");
 
@@ -191,11 +250,8 @@ mod tests {
 
     #[test]
     fn check_headers() -> Result<(), Box<dyn std::error::Error>> {
-        if HTTP_ENABLED.get().is_none() {
-            setup_http_server()?;
-        }
-        thread::sleep(Duration::from_millis(100));
-        let req = minreq::get("http://127.0.0.1:23195/check_headers")
+        let server = test_server()?;
+        let req = minreq::get(server.url("/check_headers"))
             .with_header("test", "yes")
             .send()?;
 
@@ -206,11 +262,8 @@ mod tests {
 
     #[test]
     fn check_post() -> Result<(), Box<dyn std::error::Error>> {
-        if HTTP_ENABLED.get().is_none() {
-            setup_http_server()?;
-        }
-        thread::sleep(Duration::from_millis(100));
-        let req = minreq::post("http://127.0.0.1:23195/check_post")
+        let server = test_server()?;
+        let req = minreq::post(server.url("/check_post"))
             .with_body("mateo")
             .send()?;
 
@@ -220,29 +273,941 @@ mod tests {
 
     #[test]
     fn check_wildcards() -> Result<(), Box<dyn std::error::Error>> {
-        if HTTP_ENABLED.get().is_none() {
-            setup_http_server()?;
-        }
-        thread::sleep(Duration::from_millis(100));
-        let get_req = minreq::get("http://127.0.0.1:23195/get_wildcard/tinyhttp").send()?;
+        let server = test_server()?;
+        let get_req = minreq::get(server.url("/get_wildcard/tinyhttp")).send()?;
         assert_eq!(get_req.as_str()?, "got: tinyhttp");
 
-        let post_req = minreq::post("http://127.0.0.1:23195/post_wildcard/tinyhttp").send()?;
+        let post_req = minreq::post(server.url("/post_wildcard/tinyhttp")).send()?;
         assert_eq!(post_req.as_str()?, "got: tinyhttp");
 
         Ok(())
     }
 
+    #[test]
+    fn query_strings_are_parsed() -> Result<(), Box<dyn std::error::Error>> {
+        let server = test_server()?;
+        let res = minreq::get(server.url("/ping?x=1")).send()?;
+        assert_eq!(res.as_str()?, "pong\n");
+
+        let res = minreq::get(server.url("/search?q=tiny+http%21&tag=a&tag=b")).send()?;
+        assert_eq!(res.as_str()?, "tiny http!: a,b");
+
+        let res = minreq::get(server.url("/get_wildcard/tinyhttp?x=1")).send()?;
+        assert_eq!(res.as_str()?, "got: tinyhttp");
+
+        Ok(())
+    }
+
     #[test]
     fn check_post_hello() -> Result<(), Box<dyn std::error::Error>> {
-        if HTTP_ENABLED.get().is_none() {
-            setup_http_server()?;
+        let server = test_server()?;
+        let req = minreq::post(server.url("/post_hello"))
+            .with_body("mateo")
+            .send()?;
+        assert_eq!(req.as_str()?, "Hello, mateo");
+        Ok(())
+    }
+
+    #[test]
+    fn keep_alive_reuses_connection() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let server = test_server()?;
+        fn read_response<R: BufRead>(
+            reader: &mut R,
+        ) -> Result<(String, String), Box<dyn std::error::Error>> {
+            let mut head = String::new();
+            loop {
+                let len = reader.read_line(&mut head)?;
+                if len <= 2 {
+                    break;
+                }
+            }
+            let content_length = head
+                .lines()
+                .find_map(|l| l.strip_prefix("Content-Length: "))
+                .ok_or("missing Content-Length")?
+                .parse::<usize>()?;
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            Ok((head, String::from_utf8(body)?))
         }
+
+        let mut stream = std::net::TcpStream::connect(server.addr())?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        stream.write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let (head, body) = read_response(&mut reader)?;
+        assert!(head.contains("Connection: keep-alive"));
+        assert_eq!(body, "pong\n");
+
+        // Clients may send a CRLF after a body, which is ignored rather than read as a request
+        stream.write_all(b"POST /post_hello HTTP/1.1\r\nContent-Length: 5\r\n\r\nmateo\r\n")?;
+        let (head, _) = read_response(&mut reader)?;
+        assert!(head.starts_with("HTTP/1.1 200") && head.contains("Connection: keep-alive"));
+
+        stream
+            .write_all(b"\r\nGET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let (head, body) = read_response(&mut reader)?;
+        assert!(head.contains("Connection: close"));
+        assert_eq!(body, "pong\n");
+
+        // Server should close the connection after `Connection: close`
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest)?;
+        assert!(rest.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_chunked_post() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Read, Write};
+
+        let server = test_server()?;
+        let mut stream = std::net::TcpStream::connect(server.addr())?;
+        stream.write_all(
+            b"POST /check_post HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+              Transfer-Encoding: chunked\r\n\r\n2\r\nma\r\n3;ext=1\r\nteo\r\n0\r\n\r\n",
+        )?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res)?;
+        assert!(res.ends_with("hello, mateo"));
+
+        let mut stream = std::net::TcpStream::connect(server.addr())?;
+        stream.write_all(
+            b"POST /check_post HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+              Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        )?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res)?;
+        assert!(res.starts_with("HTTP/1.1 400"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_streamed_response() -> Result<(), Box<dyn std::error::Error>> {
+        let server = test_server()?;
+        use std::io::{Read, Write};
+
+        let mut stream = std::net::TcpStream::connect(server.addr())?;
+        stream
+            .write_all(b"GET /stream HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw)?;
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!raw.to_ascii_lowercase().contains("content-length"));
+        assert!(raw.ends_with("\r\n0\r\n\r\n"));
+
+        // Framing headers a handler set itself are replaced, whatever their case
+        let mut stream = std::net::TcpStream::connect(server.addr())?;
+        stream
+            .write_all(b"GET /framed HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw)?;
+        let lower = raw.to_ascii_lowercase();
+        assert_eq!(lower.matches("content-length").count(), 1);
+        assert!(raw.contains("Content-Length: 2\r\n"));
+        assert!(!lower.contains("transfer-encoding"));
+        assert!(raw.ends_with("\r\n\r\nok"));
+
+        let res = minreq::get(server.url("/stream")).send()?;
+        assert_eq!(res.as_str()?, "streamed ".repeat(2048));
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_requests_get_error_responses() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Read, Write};
+
+        let server = test_server()?;
+        let send_raw = |raw: &[u8]| -> Result<String, Box<dyn std::error::Error>> {
+            let mut stream = std::net::TcpStream::connect(server.addr())?;
+            stream.write_all(raw)?;
+            stream.shutdown(std::net::Shutdown::Write)?;
+            let mut res = String::new();
+            stream.read_to_string(&mut res)?;
+            Ok(res)
+        };
+
+        let cases: [(&[u8], &str); 13] = [
+            (b"garbage\r\n\r\n", "HTTP/1.1 400"),
+            (b"GET /ping HTTP/2.0\r\n\r\n", "HTTP/1.1 505"),
+            (b"GET /ping HTTP/1.1\r\nno-colon\r\n\r\n", "HTTP/1.1 400"),
+            (b"GET /ping HTTP/1.1\r\n: x\r\n\r\n", "HTTP/1.1 400"),
+            (
+                b"POST /check_post HTTP/1.1\r\nContent-Length : 20\r\n\r\n\
+                  GET /ping HTTP/1.1\r\n\r\n",
+                "HTTP/1.1 400",
+            ),
+            (
+                b"GET /ping HTTP/1.1\r\nX-Bytes: \xff\xfe\r\n\r\n",
+                "HTTP/1.1 400",
+            ),
+            (
+                b"POST /check_post HTTP/1.1\r\nContent-Length: ten\r\n\r\n",
+                "HTTP/1.1 400",
+            ),
+            (
+                b"POST /check_post HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc",
+                "HTTP/1.1 400",
+            ),
+            (
+                b"POST /check_post HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc",
+                "HTTP/1.1 400",
+            ),
+            (
+                b"POST /check_post HTTP/1.1\r\nContent-Length: 30\r\nContent-Length: 0\r\n\r\n\
+                  GET /ping HTTP/1.1\r\n\r\n",
+                "HTTP/1.1 400",
+            ),
+            (
+                b"POST /check_post HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  +3\r\nabc\r\n0\r\n\r\n",
+                "HTTP/1.1 400",
+            ),
+            (
+                b"POST /check_post HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+                  0\r\n\r\n",
+                "HTTP/1.1 501",
+            ),
+            (
+                b"POST /check_post HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+                  Transfer-Encoding: identity\r\n\r\n0\r\n\r\n",
+                "HTTP/1.1 501",
+            ),
+        ];
+        for (raw, expected) in cases {
+            let res = send_raw(raw)?;
+            assert!(res.starts_with(expected), "unexpected response: {res}");
+        }
+
+        // Requests without headers are still valid
+        let res = send_raw(b"GET /ping HTTP/1.0\r\n\r\n")?;
+        assert!(res.starts_with("HTTP/1.1 200") && res.ends_with("pong\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_requests_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Read, Write};
+
+        let server = test_server()?;
+        let send_raw = |raw: &[u8]| -> Result<String, Box<dyn std::error::Error>> {
+            let mut stream = std::net::TcpStream::connect(server.addr())?;
+            stream.write_all(raw)?;
+            let mut res = String::new();
+            stream.read_to_string(&mut res)?;
+            Ok(res)
+        };
+
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000));
+        assert!(send_raw(long_target.as_bytes())?.starts_with("HTTP/1.1 414"));
+
+        let many_headers = format!("GET /ping HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(101));
+        assert!(send_raw(many_headers.as_bytes())?.starts_with("HTTP/1.1 431"));
+
+        let huge_body = b"POST /check_post HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n";
+        assert!(send_raw(huge_body)?.starts_with("HTTP/1.1 413"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn stalled_requests_time_out() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Read, Write};
+
+        let config = ping_config(|config| config.header_read_timeout(Duration::from_millis(300)));
+        let server = TestServer::start(config)?;
+
+        // Trickle the headers in slower than the deadline allows
+        let mut stream = std::net::TcpStream::connect(server.addr())?;
+        stream.write_all(b"GET /ping HTTP/1.1\r\n")?;
+        for _ in 0..4 {
+            thread::sleep(Duration::from_millis(100));
+            let _ = stream.write_all(b"X");
+        }
+
+        let mut res = String::new();
+        stream.read_to_string(&mut res)?;
+        assert!(
+            res.starts_with("HTTP/1.1 408"),
+            "unexpected response: {res}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn graceful_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+        #[get("/slow")]
+        fn slow() -> &'static str {
+            thread::sleep(Duration::from_millis(300));
+            "done"
+        }
+
+        let sock = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let config = Config::new().routes(Routes::new(vec![slow()]));
+        let handle = HttpListener::new(sock, config).spawn()?;
+        let addr = handle.local_addr().as_socket_addr().unwrap();
+
+        let in_flight = thread::spawn(move || minreq::get(format!("http://{addr}/slow")).send());
         thread::sleep(Duration::from_millis(100));
-        let req = minreq::post("http://127.0.0.1:23195/post_hello")
+
+        // The request that was already running still gets its response
+        assert!(handle.shutdown(Duration::from_secs(5)));
+        assert_eq!(in_flight.join().unwrap()?.as_str()?, "done");
+        assert!(std::net::TcpStream::connect(addr).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn systemd_socket_activation() -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::fd::OwnedFd;
+        use std::process::{Command, Stdio};
+
+        let config = ping_config(|config| config);
+
+        // The test binary runs itself again as the socket-activated child
+        if let Ok(expected) = std::env::var("TINYHTTP_SYSTEMD_ADDR") {
+            let handle = HttpListener::from_systemd_or_bind("127.0.0.1:0", config)?.spawn()?;
+            assert_eq!(handle.local_addr().to_string(), expected);
+            thread::sleep(Duration::from_secs(10));
+            return Ok(());
+        }
+
+        // Not socket-activated, so it binds the address instead
+        let http = HttpListener::from_systemd_or_bind("127.0.0.1:0", config)?;
+        let handle = http.spawn()?;
+        assert_ne!(handle.local_addr().as_socket_addr().unwrap().port(), 0);
+        handle.shutdown(Duration::from_secs(5));
+
+        // `sh` moves the socket from stdin to fd 3, and keeps its pid when it execs the child
+        let sock = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = sock.local_addr()?;
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"export LISTEN_PID=$$; exec "$0" "$@" 3<&0"#)
+            .arg(std::env::current_exe()?)
+            .args(["tests::systemd_socket_activation", "--exact", "--nocapture"])
+            .env("LISTEN_FDS", "1")
+            .env("TINYHTTP_SYSTEMD_ADDR", addr.to_string())
+            .stdin(Stdio::from(OwnedFd::from(sock)))
+            .stdout(Stdio::null())
+            .spawn()?;
+
+        let res = minreq::get(format!("http://{addr}/ping"))
+            .with_timeout(10)
+            .send();
+        let _ = child.kill();
+        child.wait()?;
+        assert_eq!(res?.as_str()?, "pong\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn serve_multiple_listeners() -> Result<(), Box<dyn std::error::Error>> {
+        let handle = Server::new(ping_config(|config| config))
+            .listener(std::net::TcpListener::bind("127.0.0.1:0")?)
+            .listener(std::net::TcpListener::bind("127.0.0.1:0")?)
+            .threads(2)
+            .spawn()?;
+
+        let addrs: Vec<_> = handle
+            .local_addrs()
+            .iter()
+            .map(|addr| addr.as_socket_addr().unwrap())
+            .collect();
+        assert_eq!(addrs.len(), 2);
+        for addr in &addrs {
+            let res = minreq::get(format!("http://{addr}/ping")).send()?;
+            assert_eq!(res.as_str()?, "pong\n");
+        }
+
+        // One handle stops every listener
+        assert!(handle.shutdown(Duration::from_secs(5)));
+        for addr in &addrs {
+            assert!(std::net::TcpStream::connect(addr).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn routes_with_named_params() {
+        #[get("/users/:id")]
+        fn user(req: Request) -> String {
+            format!("user {}", req.get_param("id").unwrap())
+        }
+
+        #[get("/users/me")]
+        fn me() -> &'static str {
+            "me"
+        }
+
+        #[get("/users/:id/posts/:post_id")]
+        fn post(req: Request) -> String {
+            let id = req.get_param("id").unwrap();
+            format!("post {} by {id}", req.get_param("post_id").unwrap())
+        }
+
+        #[get("/files/*path")]
+        fn file(req: Request) -> String {
+            format!("file {}", req.get_param("path").unwrap())
+        }
+
+        #[delete("/users/:user_id")]
+        fn delete_user(req: Request) -> String {
+            format!("deleted {}", req.get_param("user_id").unwrap())
+        }
+
+        // The static route is registered last, and still wins over the parameter
+        let routes = Routes::new(vec![user(), post(), file(), delete_user(), me()]);
+        let client = TestClient::new(Config::new().routes(routes));
+
+        assert_eq!(client.get("/users/42").body_string(), "user 42");
+        assert_eq!(client.get("/users/me").body_string(), "me");
+        assert_eq!(client.get("/users/a%20b/").body_string(), "user a b");
+        assert_eq!(
+            client.get("/users/42/posts/7?x=1").body_string(),
+            "post 7 by 42"
+        );
+        assert_eq!(
+            client.get("/files/css/site.css").body_string(),
+            "file css/site.css"
+        );
+        assert!(client.get("/users/42/posts").status_line.contains("404"));
+
+        let res = client.request(Method::DELETE, "/users/me").send();
+        assert_eq!(res.body_string(), "deleted me");
+    }
+
+    #[test]
+    fn typed_path_params() {
+        #[derive(FromParams)]
+        struct PostId {
+            id: u32,
+            post_id: u64,
+        }
+
+        #[get("/users/:id")]
+        fn user(id: Path<u32>) -> String {
+            format!("user {}", *id + 1)
+        }
+
+        #[get("/users/:id/posts/:post_id")]
+        fn post(Path((id, post_id)): Path<(u32, u64)>) -> String {
+            format!("post {post_id} by {id}")
+        }
+
+        #[post("/users/:id/posts/:post_id")]
+        fn edit_post(ids: Path<PostId>) -> String {
+            format!("edited {} by {}", ids.post_id, ids.id)
+        }
+
+        let routes = Routes::new(vec![user(), post(), edit_post()]);
+        let client = TestClient::new(Config::new().routes(routes));
+
+        assert_eq!(client.get("/users/41").body_string(), "user 42");
+        assert_eq!(client.get("/users/1/posts/2").body_string(), "post 2 by 1");
+
+        let res = client.get("/users/abc");
+        assert!(res.status_line.starts_with("HTTP/1.1 400"));
+        assert!(res.body_string().contains("`id`"));
+
+        let res = client.request(Method::POST, "/users/1/posts/2").send();
+        assert_eq!(res.body_string(), "edited 2 by 1");
+    }
+
+    #[test]
+    fn nested_route_groups() {
+        #[get("/")]
+        fn index() -> &'static str {
+            "index"
+        }
+
+        #[get("/version")]
+        fn version() -> &'static str {
+            "v1"
+        }
+
+        #[get("/:id")]
+        fn user(id: Path<u32>) -> String {
+            format!("user {}", *id)
+        }
+
+        fn require_auth(req: &mut Request) -> MiddlewareResponse {
+            match req.get_headers().get("authorization") {
+                Some(_) => MiddlewareResponse::Next,
+                None => MiddlewareResponse::Redirect(
+                    Response::new().status_line("HTTP/1.1 401 Unauthorized\r\n"),
+                ),
+            }
+        }
+
+        let users = Routes::new(vec![user()]).middleware(vec![require_auth]);
+        let api = Routes::new(vec![version()]).nest("/users", users);
+        let routes = Routes::new(vec![index()]).merge(Routes::new(vec![]).nest("/api/v1/", api));
+        let client = TestClient::new(Config::new().routes(routes));
+
+        assert_eq!(client.get("/").body_string(), "index");
+        assert_eq!(client.get("/api/v1/version").body_string(), "v1");
+        assert!(client
+            .get("/api/v1/users/7")
+            .status_line
+            .starts_with("HTTP/1.1 401"));
+
+        let res = client
+            .request(Method::GET, "/api/v1/users/7")
+            .header("Authorization", "Bearer token")
+            .send();
+        assert_eq!(res.body_string(), "user 7");
+    }
+
+    #[test]
+    fn build_urls_from_route_names() {
+        use crate::internal::config::UrlError;
+
+        #[get("/users/:id")]
+        fn get_user(id: Path<u32>) -> String {
+            format!("user {}", *id)
+        }
+
+        #[get("/*path", name = "static_file")]
+        fn file(req: Request) -> String {
+            req.get_param("path").unwrap().to_string()
+        }
+
+        let routes = Routes::new(vec![file()]).nest("/api", Routes::new(vec![get_user()]));
+        let config = Config::new().routes(routes);
+
+        assert_eq!(
+            config.url_for("get_user", &[("id", "42")]),
+            Ok("/api/users/42".into())
+        );
+        assert_eq!(
+            config.url_for("static_file", &[("path", "css/a b.css"), ("v", "1&2")]),
+            Ok("/css/a%20b.css?v=1%262".into())
+        );
+        assert_eq!(
+            config.url_for("get_user", &[]),
+            Err(UrlError::MissingParam {
+                route: "get_user".into(),
+                param: "id".into()
+            })
+        );
+        assert_eq!(
+            config.url_for("file", &[]),
+            Err(UrlError::UnknownRoute("file".into()))
+        );
+    }
+
+    #[test]
+    fn list_registered_routes() {
+        use crate::internal::config::RouteInfo;
+
+        #[get("/users/:id")]
+        fn get_user() -> &'static str {
+            "old"
+        }
+
+        #[get("/users/:user_id", name = "user")]
+        fn get_user_again() -> &'static str {
+            "new"
+        }
+
+        #[post("/files/*path")]
+        fn upload() -> &'static str {
+            "uploaded"
+        }
+
+        let routes = Routes::new(vec![upload(), get_user(), get_user_again()]);
+        let config = Config::new().routes(routes).routes_endpoint("/_routes");
+
+        // The second /users route replaces the first
+        assert_eq!(
+            config.route_table(),
+            [
+                RouteInfo {
+                    method: Method::POST,
+                    pattern: "/files/*path".into(),
+                    params: vec!["path".into()],
+                    name: Some("upload".into()),
+                },
+                RouteInfo {
+                    method: Method::GET,
+                    pattern: "/users/:user_id".into(),
+                    params: vec!["user_id".into()],
+                    name: Some("user".into()),
+                },
+            ]
+        );
+
+        let client = TestClient::new(config);
+        assert_eq!(
+            client.get("/_routes").body_string(),
+            "METHOD  PATTERN         NAME\n\
+             POST    /files/*path    upload\n\
+             GET     /users/:user_id user\n"
+        );
+        assert_eq!(
+            client.get("/_routes?format=json").body_string(),
+            r#"[{"method":"POST","pattern":"/files/*path","params":["path"],"name":"upload"},"#
+                .to_string()
+                + r#"{"method":"GET","pattern":"/users/:user_id","params":["user_id"],"name":"user"}]"#
+        );
+    }
+
+    #[test]
+    fn custom_error_handlers() {
+        #[get("/item")]
+        fn item() -> &'static str {
+            "item"
+        }
+
+        #[get("/gone")]
+        fn gone() -> Response {
+            Response::new().status_line("HTTP/1.1 410 Gone")
+        }
+
+        #[catch(404)]
+        fn not_found(req: &Request) -> String {
+            format!("nothing at {}", req.get_path())
+        }
+
+        fn json_error(status: StatusCode, _req: &Request) -> Response {
+            let body = format!("{{\"error\":{}}}", status.as_u16());
+            Response::new()
+                .body(body.into_bytes())
+                .mime("application/json")
+        }
+
+        let config = Config::new()
+            .routes(Routes::new(vec![item(), gone()]))
+            .catchers(vec![not_found()])
+            .error_handler(json_error);
+        let client = TestClient::new(config);
+
+        let res = client.get("/missing");
+        assert!(res.status_line.starts_with("HTTP/1.1 404"));
+        assert_eq!(res.body_string(), "nothing at /missing");
+
+        let res = client.request(Method::DELETE, "/item").send();
+        assert!(res.status_line.starts_with("HTTP/1.1 405"));
+        assert_eq!(res.headers["Allow"], "GET, HEAD");
+        assert_eq!(res.body_string(), r#"{"error":405}"#);
+
+        // Routes returning an error without a body get one from the handlers
+        let res = client.get("/gone");
+        assert!(res.status_line.starts_with("HTTP/1.1 410"));
+        assert_eq!(res.body_string(), r#"{"error":410}"#);
+    }
+
+    #[test]
+    fn closure_routes_capture_state() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let greeting = String::from("hello");
+
+        let config = ping_config(|config| {
+            config
+                .named_route(Method::POST, "/hit", "hit", move |_req| {
+                    counter.fetch_add(1, Ordering::SeqCst).to_string()
+                })
+                .routes(Routes::new(vec![]).named_route(
+                    Method::GET,
+                    "/greet/:name",
+                    "greet",
+                    move |req| format!("{greeting}, {}", req.get_param("name").unwrap()),
+                ))
+                .route(Method::GET, "/anonymous", |_req| "anonymous")
+        });
+
+        assert_eq!(config.url_for("hit", &[]).unwrap(), "/hit");
+        assert_eq!(
+            config.url_for("greet", &[("name", "tiny")]).unwrap(),
+            "/greet/tiny"
+        );
+        let names: Vec<_> = config.route_table().into_iter().map(|r| r.name).collect();
+        assert!(names.contains(&Some("greet".to_string())));
+        assert!(names.contains(&None));
+
+        let client = TestClient::new(config);
+
+        let hit = || client.request(Method::POST, "/hit").send();
+        assert_eq!(hit().body_string(), "0");
+        assert_eq!(hit().body_string(), "1");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        assert_eq!(client.get("/greet/tiny").body_string(), "hello, tiny");
+        assert_eq!(client.get("/ping").body_string(), "pong\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "must be the last segment")]
+    fn catch_all_must_end_the_pattern() {
+        let _ = Config::new().route(Method::GET, "/files/*path/meta", |_req| "meta");
+    }
+
+    #[test]
+    fn mount_point_paths_are_normalized() -> Result<(), Box<dyn std::error::Error>> {
+        let root = std::env::temp_dir().join(format!("tinyhttp-mount-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("public"))?;
+        std::fs::write(root.join("public/hello world.txt"), "hello")?;
+        std::fs::write(root.join("secret.txt"), "secret")?;
+
+        #[post("/upload")]
+        fn upload() -> &'static str {
+            "ok"
+        }
+
+        let mount = root.join("public");
+        let client = TestClient::new(
+            Config::new()
+                .mount_point(mount.to_str().unwrap())
+                .routes(Routes::new(vec![upload()])),
+        );
+
+        let res = client.get("/hello%20world.txt");
+        assert_eq!(res.body.as_deref(), Some(&b"hello"[..]));
+
+        let res = client.get("/./missing/../hello%20world.txt?x=1");
+        assert_eq!(res.body.as_deref(), Some(&b"hello"[..]));
+
+        for path in [
+            "/../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/a/../..%2fsecret.txt",
+        ] {
+            let res = client.get(path);
+            assert!(
+                res.status_line.starts_with("HTTP/1.1 400"),
+                "{path} was served"
+            );
+        }
+
+        // Paths only routed for other methods aren't looked up as files
+        let res = client.get("/upload");
+        assert!(res.status_line.starts_with("HTTP/1.1 405"));
+        assert_eq!(res.headers.get("Allow").map(String::as_str), Some("POST"));
+
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_dispatches_without_socket() {
+        #[post("/echo")]
+        fn echo(body: Option<&str>) -> String {
+            body.unwrap().to_string()
+        }
+
+        #[delete("/echo")]
+        fn clear() -> Response {
+            Response::new().status_line("HTTP/1.1 204 No Content")
+        }
+
+        let config = ping_config(|config| {
+            config
+                .routes(Routes::new(vec![echo(), clear()]))
+                .headers(vec!["X-Test: yes".to_string()])
+                .gzip(true)
+        });
+        let client = TestClient::new(config);
+
+        let res = client.get("/ping");
+        assert_eq!(res.body.as_deref(), Some(&b"pong\n"[..]));
+        assert_eq!(res.headers["Content-Length"], "5");
+        assert_eq!(res.headers["X-Test"], "yes");
+
+        let res = client
+            .request(Method::POST, "/echo")
+            .header("Accept-Encoding", "gzip, deflate")
+            .body("mateo")
+            .send();
+        assert_eq!(res.headers["Content-Encoding"], "gzip");
+
+        let res = client
+            .request(Method::DELETE, "/echo")
+            .header("Accept-Encoding", "gzip")
+            .send();
+        assert!(res.status_line.starts_with("HTTP/1.1 204"));
+        assert!(!res.headers.contains_key("Content-Encoding"));
+        assert!(res.body.is_none());
+
+        let res = client.get("/missing");
+        assert!(res.status_line.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn handlers_can_use_the_connection() -> Result<(), Box<dyn std::error::Error>> {
+        fn peer(_req: &mut Request, conn: &mut dyn Connection) -> Response {
+            let peer_addr = conn.peer_addr().unwrap().as_socket_addr().unwrap();
+            peer_addr.ip().to_string().into()
+        }
+
+        let route = RouteWithReqAndRes::new().set_path("/peer").set_body(peer);
+        let config = Config::new().routes(Routes::new(vec![Box::new(route) as Box<dyn Route>]));
+        let server = TestServer::start(config)?;
+
+        let res = minreq::get(server.url("/peer")).send()?;
+        assert_eq!(res.as_str()?, "127.0.0.1");
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serve_on_unix_socket() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::internal::listener::bind_unix;
+        use std::io::{Read, Write};
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        let path = std::env::temp_dir().join(format!("tinyhttp-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // Leave a stale socket file behind, like a server that crashed
+        drop(UnixListener::bind(&path)?);
+        let socket = bind_unix(&path)?;
+
+        let handle = HttpListener::new(socket, ping_config(|config| config)).spawn()?;
+
+        let err = bind_unix(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+        let mut stream = UnixStream::connect(&path)?;
+        stream.write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res)?;
+        assert!(res.starts_with("HTTP/1.1 200") && res.ends_with("pong\n"));
+
+        assert!(handle.shutdown(Duration::from_secs(5)));
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn shutdown_wakes_abstract_unix_sockets() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::internal::connection::ConnectionAddr;
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixListener};
+
+        // Abstract sockets have no path, but shutdown still has to wake their accept loop
+        let name = format!("tinyhttp-{}", std::process::id());
+        let socket = UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?)?;
+
+        let handle = HttpListener::new(socket, Config::new()).spawn()?;
+        assert!(matches!(handle.local_addr(), ConnectionAddr::Unix(None)));
+        assert!(handle.shutdown(Duration::from_secs(5)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_other_methods() -> Result<(), Box<dyn std::error::Error>> {
+        let server = test_server()?;
+        let res = minreq::put(server.url("/item/42"))
             .with_body("mateo")
             .send()?;
-        assert_eq!(req.as_str()?, "Hello, mateo");
+        assert_eq!(res.as_str()?, "put 42: mateo");
+
+        let res = minreq::patch(server.url("/item"))
+            .with_body("mateo")
+            .send()?;
+        assert_eq!(res.as_str()?, "patched mateo");
+
+        let res = minreq::delete(server.url("/item")).send()?;
+        assert_eq!(res.as_str()?, "deleted");
+
+        let res = minreq::options(server.url("/item")).send()?;
+        assert_eq!(res.status_code, 204);
+        assert_eq!(
+            res.headers.get("allow").map(|s| s.as_str()),
+            Some("PUT, PATCH, DELETE, OPTIONS")
+        );
+
+        let res = minreq::Request::new(
+            minreq::Method::Custom("BREW".to_string()),
+            server.url("/item"),
+        )
+        .send()?;
+        assert_eq!(res.status_code, 501);
+
+        Ok(())
+    }
+
+    #[test]
+    fn responses_are_framed() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Read, Write};
+
+        let server = test_server()?;
+        let mut stream = std::net::TcpStream::connect(server.addr())?;
+        stream.write_all(b"GET /ping HTTP/1.0\r\n\r\n")?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res)?;
+
+        let (head, body) = res.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nContent-Length: 5"));
+        assert!(head.contains("\r\nContent-Type: text/plain"));
+        assert!(head.contains("\r\nConnection: close"));
+        assert!(head.contains(" GMT"));
+        assert!(head.lines().any(|l| l.starts_with("Date: ")));
+        assert_eq!(body, "pong\n");
+
+        let mut stream = std::net::TcpStream::connect(server.addr())?;
+        stream.write_all(b"OPTIONS /item HTTP/1.0\r\n\r\n")?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res)?;
+
+        let (head, body) = res.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(body, "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_head_and_method_not_allowed() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Read, Write};
+
+        let server = test_server()?;
+        let mut stream = std::net::TcpStream::connect(server.addr())?;
+        stream.write_all(b"HEAD /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res)?;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains("Content-Length: 5\r\n"));
+        assert!(res.ends_with("\r\n\r\n"));
+
+        let res = minreq::post(server.url("/ping")).send()?;
+        assert_eq!(res.status_code, 405);
+        assert_eq!(
+            res.headers.get("allow").map(|s| s.as_str()),
+            Some("GET, HEAD")
+        );
+
+        let res = minreq::get(server.url("/item")).send()?;
+        assert_eq!(res.status_code, 405);
+        assert_eq!(
+            res.headers.get("allow").map(|s| s.as_str()),
+            Some("PUT, PATCH, DELETE, OPTIONS")
+        );
+
         Ok(())
     }
 }